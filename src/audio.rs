@@ -1,8 +1,26 @@
-use std::cell::Cell;
-
+use std::collections::vec_deque::Drain;
+use std::rc::Rc;
+
+mod dmc;
+mod envelope;
+mod filter;
+mod length;
+mod mixer;
+mod noise;
 mod pulse;
+mod resample;
+mod triangle;
+
+use dmc::Dmc;
+pub use filter::FilterPreset;
+use mixer::Mixer;
+use noise::Noise;
+use pulse::{Complement, Pulse};
+use resample::Resampler;
+use triangle::Triangle;
 
-use pulse::{Counter, Pulse};
+use crate::cpu::{Interrupts, IrqSource};
+use crate::state::{Reader, Snapshot, StateError, Writer};
 
 pub struct Apu {
     pulse_1: Pulse,
@@ -11,11 +29,16 @@ pub struct Apu {
     triangle: Triangle,
 
     dmc: Dmc,
-    frame_int: Cell<bool>,
+    lines: Rc<Interrupts>,
+
+    mixer: Mixer,
+    output: Resampler,
 
     counter: u16,
     mode: Mode,
     int_inhibit: bool,
+    even: bool,
+    reset_delay: Option<u8>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -24,168 +47,163 @@ enum Mode {
     Step5,
 }
 
-struct Noise {
-    counter: u8,
-    enabled: bool,
-}
-
-struct Triangle {
-    counter: u8,
-    enabled: bool,
-}
-
-struct Dmc {
-    enabled: bool,
-    bytes: u8,
-    interupt: bool,
-}
-
-impl Noise {
-    fn new() -> Self {
-        Self {
-            counter: 0,
-            enabled: false,
-        }
-    }
-
-    fn active(&self) -> bool { self.counter > 0 && self.enabled }
-
-    fn halt(&mut self) { self.enabled = false; }
-
-    fn disable(&mut self) {
-        self.halt();
-        self.counter = 0;
-    }
-
-    fn enable(&mut self) { self.enabled = true; }
-}
-
-impl Triangle {
-    fn new() -> Self {
-        Self {
-            counter: 0,
-            enabled: false,
-        }
-    }
-
-    fn active(&self) -> bool { self.counter > 0 && self.enabled }
-
-    fn halt(&mut self) { self.enabled = false; }
-
-    fn disable(&mut self) {
-        self.halt();
-        self.counter = 0;
-    }
-
-    fn enable(&mut self) { self.enabled = true; }
-}
-
-impl Dmc {
-    fn new() -> Self {
-        Self {
-            enabled: false,
-            interupt: false,
-            bytes: 0,
-        }
-    }
-
-    fn disable(&mut self) {
-        self.enabled = false;
-        self.bytes = 0;
-    }
-
-    fn enable(&mut self) { self.enabled = true; }
-}
-
-const LENGTH_TABLE: [u8; 32] = [
-    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
-    192, 24, 72, 26, 16, 38, 32, 30,
-];
-
 impl Apu {
-    pub fn new() -> Self {
+    pub fn new(lines: Rc<Interrupts>) -> Self {
         Apu {
-            pulse_1: Pulse::new(),
-            pulse_2: Pulse::new(),
+            pulse_1: Pulse::new(Complement::Ones),
+            pulse_2: Pulse::new(Complement::Twos),
             noise: Noise::new(),
             triangle: Triangle::new(),
             dmc: Dmc::new(),
-            frame_int: Cell::new(false),
+            lines,
+
+            mixer: Mixer::new(),
+            output: Resampler::new(44_100),
 
             counter: 0,
             mode: Mode::Step4,
             int_inhibit: false,
+            even: false,
+            reset_delay: None,
         }
     }
 
+    // Clocked once per CPU cycle; the frame counter steps are in CPU cycles.
     pub fn clock(&mut self) {
-        self.counter += 1;
-        let (_quarter, half) = match (self.counter, self.mode) {
-            (3728, _) => (true, false),
-            (7456, _) => (true, true),
-            (11185, _) => (true, false),
-            (14914, Mode::Step4) => {
-                if !self.int_inhibit {
-                    self.frame_int.set(true);
-                }
+        self.even = !self.even;
+
+        let reset = self.reset_delay == Some(1);
+        self.reset_delay = self.reset_delay.and_then(|d| d.checked_sub(1)).filter(|&d| d > 0);
+
+        if reset {
+            self.counter = 0;
+        } else {
+            self.counter += 1;
+        }
+
+        let (quarter, half) = match (self.counter, self.mode) {
+            (0, Mode::Step5) if reset => (true, true),
+            (7457, _) => (true, false),
+            (14913, _) => (true, true),
+            (22371, _) => (true, false),
+            (29828, Mode::Step4) => {
+                self.frame_interrupt();
+                (false, false)
+            }
+            (29829, Mode::Step4) => {
+                self.frame_interrupt();
                 (true, true)
             }
-            (14915, Mode::Step4) => {
+            (29830, Mode::Step4) => {
+                self.frame_interrupt();
                 self.counter = 0;
                 (false, false)
             }
-            (18640, Mode::Step5) => (true, true),
-            (18641, Mode::Step5) => {
+            (37281, Mode::Step5) => (true, true),
+            (37282, Mode::Step5) => {
                 self.counter = 0;
                 (false, false)
             }
             (_, _) => (false, false),
         };
 
+        if quarter {
+            self.pulse_1.clock_envelope();
+            self.pulse_2.clock_envelope();
+            self.noise.clock_envelope();
+            self.triangle.clock_linear();
+        }
+
         if half {
-            self.pulse_1.counter.as_mut().map(Counter::clock);
-            self.pulse_2.counter.as_mut().map(Counter::clock);
+            self.pulse_1.clock_sweep();
+            self.pulse_2.clock_sweep();
+            self.pulse_1.counter.clock();
+            self.pulse_2.counter.clock();
+            self.triangle.counter.clock();
+            self.noise.counter.clock();
         }
 
-        self.pulse_1.clock();
-        self.pulse_2.clock();
+        if self.even {
+            self.pulse_1.clock();
+            self.pulse_2.clock();
+        }
+        self.triangle.clock();
+        self.noise.clock();
+        self.dmc.clock();
+
+        let sample = self.mixer.mix(
+            self.pulse_1.output(),
+            self.pulse_2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        );
+        self.output.push(sample);
+    }
+
+    // Silences every channel and restarts the frame counter as if $4017 was written again.
+    pub fn reset(&mut self) {
+        self.write(0x4015, 0);
+        self.dmc.reset();
+        self.lines.ack_irq(IrqSource::FrameCounter);
+        let frame = (matches!(self.mode, Mode::Step5) as u8) << 7 | (self.int_inhibit as u8) << 6;
+        self.write(0x4017, frame);
+    }
+
+    // Everything but the host output settings starts over, with $4017 written as $00.
+    pub fn power_on(&mut self) {
+        let mut apu = Apu::new(self.lines.clone());
+        std::mem::swap(&mut apu.output, &mut self.output);
+        *self = apu;
+        self.lines.ack_irq(IrqSource::FrameCounter);
+        self.lines.ack_irq(IrqSource::Dmc);
+        self.write(0x4017, 0);
+    }
+
+    pub fn sample_rate(&self) -> u32 { self.output.rate }
+
+    pub fn set_sample_rate(&mut self, rate: u32) { self.output.set_rate(rate); }
+
+    pub fn set_filter(&mut self, preset: FilterPreset) { self.output.set_filter(preset); }
+
+    pub fn samples(&mut self) -> Drain<'_, f32> { self.output.drain() }
+
+    // DMC sample fetches go through the CPU bus, stalling the CPU while they happen.
+    pub fn dmc_request(&mut self) -> Option<u16> { self.dmc.request() }
+
+    pub fn dmc_fill(&mut self, val: u8) {
+        if self.dmc.fill(val) {
+            self.lines.raise_irq(IrqSource::Dmc);
+        }
+    }
+
+    fn frame_interrupt(&self) {
+        if !self.int_inhibit {
+            self.lines.raise_irq(IrqSource::FrameCounter);
+        }
     }
 
     pub fn get_status(&self) -> u8 {
-        self.pulse_1.active() as u8
+        let status = self.pulse_1.active() as u8
             | (self.pulse_2.active() as u8) << 1
             | (self.noise.active() as u8) << 2
             | (self.triangle.active() as u8) << 3
             | ((self.dmc.bytes > 0) as u8) << 4
-            | (self.frame_int.get() as u8) << 6
-            | (self.dmc.interupt as u8) << 7
+            | (self.lines.is_raised(IrqSource::FrameCounter) as u8) << 6
+            | (self.lines.is_raised(IrqSource::Dmc) as u8) << 7;
+        self.lines.ack_irq(IrqSource::FrameCounter);
+        status
     }
 
     pub fn write(&mut self, idx: u16, val: u8) {
-        if val == 0 {
-            return;
-        }
         match idx {
             0x4015 => {
                 self.pulse_1.set_enabled(val & 1 != 0);
                 self.pulse_2.set_enabled(val & (1 << 1) != 0);
-
-                if val & (1 << 2) != 0 {
-                    self.triangle.enable();
-                } else {
-                    self.triangle.disable();
-                }
-                if val & (1 << 3) != 0 {
-                    self.noise.enable();
-                } else {
-                    self.noise.disable();
-                }
-                if val & (1 << 4) != 0 {
-                    self.dmc.enable();
-                } else {
-                    self.dmc.disable();
-                }
-                self.dmc.interupt = false;
+                self.triangle.set_enabled(val & (1 << 2) != 0);
+                self.noise.set_enabled(val & (1 << 3) != 0);
+                self.dmc.set_enabled(val & (1 << 4) != 0);
+                self.lines.ack_irq(IrqSource::Dmc);
             }
             0x4017 => {
                 self.mode = if val & 0x80 != 0 {
@@ -194,34 +212,75 @@ impl Apu {
                     Mode::Step4
                 };
                 self.int_inhibit = val & 0x40 != 0;
+                if self.int_inhibit {
+                    self.lines.ack_irq(IrqSource::FrameCounter);
+                }
+                // The sequencer restarts 3 or 4 CPU cycles after the write, depending on alignment.
+                self.reset_delay = Some(if self.even { 4 } else { 5 });
             }
 
             0x4000 => self.pulse_1.write_reg_0(val),
-            0x4001 => (), // todo!("Pulse Channel 1 Sweep [{:08b}]", val),
-            0x4002 => self.pulse_1.write_reg_2(val), // todo!("Pulse Channel 1 Timer Low [{:08b}]", val),
+            0x4001 => self.pulse_1.write_reg_1(val),
+            0x4002 => self.pulse_1.write_reg_2(val),
             0x4003 => self.pulse_1.write_reg_3(val),
 
-            0x4004 => (), // todo!("Pulse Channel 2 Control [{:08b}]", val),
-            0x4005 => (), // todo!("Pulse Channel 2 Sweep [{:08b}]", val),
-            0x4006 => (), // todo!("Pulse Channel 2 Timer Low [{:08b}]", val),
-            0x4007 => (), // todo!("Pulse Channel 2 Counter [{:08b}]", val),
+            0x4004 => self.pulse_2.write_reg_0(val),
+            0x4005 => self.pulse_2.write_reg_1(val),
+            0x4006 => self.pulse_2.write_reg_2(val),
+            0x4007 => self.pulse_2.write_reg_3(val),
 
-            0x4008 => (), // todo!("Triangle Channel Control [{:08b}]", val),
-            0x4009 => (), // todo!("Triangle Channel Invalid [{:08b}]", val),
-            0x400A => (), // todo!("Triangle Channel Timer Low [{:08b}]", val),
-            0x400B => (), // todo!("Triangle Channel Counter [{:08b}]", val),
+            0x4008 => self.triangle.write_reg_0(val),
+            0x4009 => (),
+            0x400A => self.triangle.write_reg_2(val),
+            0x400B => self.triangle.write_reg_3(val),
 
-            0x400C => (), // todo!("Noise Channel Control [{:08b}]", val),
-            0x400D => (), // todo!("Noise Channel Invalid [{:08b}]", val),
-            0x400E => (), // todo!("Noise Channel Modifier [{:08b}]", val),
-            0x400F => (), // todo!("Noise Channel Counter [{:08b}]", val),
+            0x400C => self.noise.write_reg_0(val),
+            0x400D => (),
+            0x400E => self.noise.write_reg_2(val),
+            0x400F => self.noise.write_reg_3(val),
 
-            0x4010 => (), // todo!("DMC Channel Control [{:08b}]", val),
-            0x4011 => (), // todo!("DMC Channel Sweep [{:08b}]", val),
-            0x4012 => (), // todo!("DMC Channel Timer Low [{:08b}]", val),
-            0x4013 => (), // todo!("DMC Channel Counter [{:08b}]", val),
+            0x4010 => {
+                if self.dmc.write_reg_0(val) {
+                    self.lines.ack_irq(IrqSource::Dmc);
+                }
+            }
+            0x4011 => self.dmc.write_reg_1(val),
+            0x4012 => self.dmc.write_reg_2(val),
+            0x4013 => self.dmc.write_reg_3(val),
 
             _ => todo!("Unknown address: {:04X}", idx),
         }
     }
 }
+
+// Only the emulated hardware is saved; the mixer and output stage keep their host settings.
+impl Snapshot for Apu {
+    fn save(&self, w: &mut Writer) {
+        self.pulse_1.save(w);
+        self.pulse_2.save(w);
+        self.noise.save(w);
+        self.triangle.save(w);
+        self.dmc.save(w);
+
+        w.u16(self.counter);
+        w.bool(matches!(self.mode, Mode::Step5));
+        w.bool(self.int_inhibit);
+        w.bool(self.even);
+        w.u8(self.reset_delay.unwrap_or(0));
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.pulse_1.load(r)?;
+        self.pulse_2.load(r)?;
+        self.noise.load(r)?;
+        self.triangle.load(r)?;
+        self.dmc.load(r)?;
+
+        self.counter = r.u16()?;
+        self.mode = if r.bool()? { Mode::Step5 } else { Mode::Step4 };
+        self.int_inhibit = r.bool()?;
+        self.even = r.bool()?;
+        self.reset_delay = Some(r.u8()?).filter(|&d| d > 0);
+        Ok(())
+    }
+}
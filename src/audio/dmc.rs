@@ -0,0 +1,194 @@
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
+// Output rates in CPU cycles.
+const RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    period: u16,
+    timer: u16,
+
+    sample_addr: u16,
+    sample_len: u16,
+    addr: u16,
+    pub bytes: u16,
+    buffer: Option<u8>,
+    fetching: bool,
+
+    shift: u8,
+    bits: u8,
+    silence: bool,
+    output: u8,
+}
+
+impl Dmc {
+    pub fn new() -> Self {
+        Self {
+            irq_enabled: false,
+            looping: false,
+            period: RATES[0],
+            timer: RATES[0],
+
+            sample_addr: 0xC000,
+            sample_len: 1,
+            addr: 0xC000,
+            bytes: 0,
+            buffer: None,
+            fetching: false,
+
+            shift: 0,
+            bits: 8,
+            silence: true,
+            output: 0,
+        }
+    }
+
+    pub fn clock(&mut self) {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period;
+
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.output <= 125 {
+                    self.output += 2;
+                }
+            } else if self.output >= 2 {
+                self.output -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits -= 1;
+        if self.bits == 0 {
+            self.bits = 8;
+            match self.buffer.take() {
+                Some(byte) => {
+                    self.silence = false;
+                    self.shift = byte;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // The address of the next sample byte, once the buffer needs refilling.
+    pub fn request(&mut self) -> Option<u16> {
+        if self.buffer.is_some() || self.bytes == 0 || self.fetching {
+            return None;
+        }
+        self.fetching = true;
+        Some(self.addr)
+    }
+
+    // Returns whether finishing the sample should raise an IRQ.
+    pub fn fill(&mut self, byte: u8) -> bool {
+        self.fetching = false;
+        self.buffer = Some(byte);
+        // The address wraps around to $8000 rather than $0000.
+        self.addr = self.addr.checked_add(1).unwrap_or(0x8000);
+        self.bytes -= 1;
+
+        if self.bytes == 0 {
+            if self.looping {
+                self.restart();
+            } else {
+                return self.irq_enabled;
+            }
+        }
+        false
+    }
+
+    pub fn output(&self) -> u8 { self.output }
+
+    // Any fetch in progress is abandoned, and only the lowest bit of the output level survives.
+    pub fn reset(&mut self) {
+        self.fetching = false;
+        self.output &= 1;
+    }
+
+    fn restart(&mut self) {
+        self.addr = self.sample_addr;
+        self.bytes = self.sample_len;
+    }
+
+    pub fn set_enabled(&mut self, enable: bool) {
+        if !enable {
+            self.bytes = 0;
+        } else if self.bytes == 0 {
+            self.restart();
+        }
+    }
+
+    // Returns whether the IRQ flag should be cleared.
+    pub fn write_reg_0(&mut self, val: u8) -> bool {
+        self.irq_enabled = val & 0x80 != 0;
+        self.looping = val & 0x40 != 0;
+        self.period = RATES[usize::from(val & 0x0F)];
+        !self.irq_enabled
+    }
+
+    pub fn write_reg_1(&mut self, val: u8) { self.output = val & 0x7F; }
+
+    pub fn write_reg_2(&mut self, val: u8) { self.sample_addr = 0xC000 | (val as u16) << 6; }
+
+    pub fn write_reg_3(&mut self, val: u8) { self.sample_len = (val as u16) << 4 | 1; }
+}
+
+impl Snapshot for Dmc {
+    fn save(&self, w: &mut Writer) {
+        w.bool(self.irq_enabled);
+        w.bool(self.looping);
+        w.u16(self.period);
+        w.u16(self.timer);
+
+        w.u16(self.sample_addr);
+        w.u16(self.sample_len);
+        w.u16(self.addr);
+        w.u16(self.bytes);
+        w.bool(self.buffer.is_some());
+        w.u8(self.buffer.unwrap_or(0));
+        w.bool(self.fetching);
+
+        w.u8(self.shift);
+        w.u8(self.bits);
+        w.bool(self.silence);
+        w.u8(self.output);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.irq_enabled = r.bool()?;
+        self.looping = r.bool()?;
+        self.period = r.u16()?;
+        self.timer = r.u16()?;
+
+        self.sample_addr = r.u16()?;
+        self.sample_len = r.u16()?;
+        self.addr = r.u16()?;
+        self.bytes = r.u16()?;
+        let full = r.bool()?;
+        let buffer = r.u8()?;
+        self.buffer = if full { Some(buffer) } else { None };
+        self.fetching = r.bool()?;
+
+        self.shift = r.u8()?;
+        self.bits = r.u8()?;
+        self.silence = r.bool()?;
+        self.output = r.u8()?;
+
+        // Both counters are decremented before being checked, so can't start at 0.
+        if !RATES.contains(&self.period) || self.timer == 0 {
+            return Err(StateError::Invalid("DMC timer"));
+        }
+        if !(1..=8).contains(&self.bits) {
+            return Err(StateError::Invalid("DMC bit count"));
+        }
+        if self.output > 0x7F {
+            return Err(StateError::Invalid("DMC output"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
+pub struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Self {
+            start: false,
+            looping: false,
+            constant: false,
+            volume: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    // Shares its register with the length counter, whose halt flag doubles as the loop flag.
+    pub fn write(&mut self, val: u8) {
+        self.looping = val & 0x20 != 0;
+        self.constant = val & 0x10 != 0;
+        self.volume = val & 0x0F;
+    }
+
+    pub fn restart(&mut self) { self.start = true; }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+impl Snapshot for Envelope {
+    fn save(&self, w: &mut Writer) {
+        w.bool(self.start);
+        w.bool(self.looping);
+        w.bool(self.constant);
+        w.u8(self.volume);
+        w.u8(self.divider);
+        w.u8(self.decay);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.start = r.bool()?;
+        self.looping = r.bool()?;
+        self.constant = r.bool()?;
+        self.volume = r.u8()?;
+        self.divider = r.u8()?;
+        self.decay = r.u8()?;
+        // Either one is the channel's output, which the mixer expects to be 4 bits.
+        if self.volume > 15 || self.decay > 15 {
+            return Err(StateError::Invalid("envelope"));
+        }
+        Ok(())
+    }
+}
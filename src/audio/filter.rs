@@ -0,0 +1,81 @@
+use std::f32::consts::PI;
+
+// The analog stages between the APU and the audio out jack.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FilterPreset {
+    // Front-loading NES: high-pass at 90Hz and 440Hz, then low-pass at 14kHz.
+    Nes,
+    // Famicom: a single high-pass at 37Hz, then low-pass at 14kHz.
+    Famicom,
+    // The mixer output as is, DC offset and all.
+    Raw,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Kind {
+    HighPass,
+    LowPass,
+}
+
+// A first-order RC filter.
+#[derive(Debug, Copy, Clone)]
+struct Filter {
+    kind: Kind,
+    alpha: f32,
+    prev_in: f32,
+    prev_out: f32,
+}
+
+pub struct FilterChain {
+    preset: FilterPreset,
+    filters: Vec<Filter>,
+}
+
+impl Filter {
+    fn new(kind: Kind, cutoff: f32, rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / rate as f32;
+        let alpha = match kind {
+            Kind::HighPass => rc / (rc + dt),
+            Kind::LowPass => dt / (rc + dt),
+        };
+        Self {
+            kind,
+            alpha,
+            prev_in: 0.0,
+            prev_out: 0.0,
+        }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        let out = match self.kind {
+            Kind::HighPass => self.alpha * (self.prev_out + sample - self.prev_in),
+            Kind::LowPass => self.prev_out + self.alpha * (sample - self.prev_out),
+        };
+        self.prev_in = sample;
+        self.prev_out = out;
+        out
+    }
+}
+
+impl FilterChain {
+    pub fn new(preset: FilterPreset, rate: u32) -> Self {
+        let filters = match preset {
+            FilterPreset::Nes => vec![
+                Filter::new(Kind::HighPass, 90.0, rate),
+                Filter::new(Kind::HighPass, 440.0, rate),
+                Filter::new(Kind::LowPass, 14_000.0, rate),
+            ],
+            FilterPreset::Famicom => vec![
+                Filter::new(Kind::HighPass, 37.0, rate),
+                Filter::new(Kind::LowPass, 14_000.0, rate),
+            ],
+            FilterPreset::Raw => Vec::new(),
+        };
+        Self { preset, filters }
+    }
+
+    pub fn preset(&self) -> FilterPreset { self.preset }
+
+    pub fn process(&mut self, sample: f32) -> f32 { self.filters.iter_mut().fold(sample, |s, f| f.process(s)) }
+}
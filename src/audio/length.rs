@@ -0,0 +1,61 @@
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 38, 32, 30,
+];
+
+pub struct LengthCounter {
+    enabled: bool,
+    halted: bool,
+    count: u8,
+}
+
+impl LengthCounter {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            halted: false,
+            count: 0,
+        }
+    }
+
+    pub fn active(&self) -> bool { self.count > 0 }
+
+    pub fn set_enabled(&mut self, enable: bool) {
+        self.enabled = enable;
+        if !enable {
+            self.count = 0;
+        }
+    }
+
+    pub fn set_halted(&mut self, halt: bool) { self.halted = halt; }
+
+    // Reloads are ignored while the channel is disabled through $4015.
+    pub fn load(&mut self, idx: u8) {
+        if self.enabled {
+            self.count = LENGTH_TABLE[usize::from(idx & 0x1F)];
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.halted && self.count > 0 {
+            self.count -= 1;
+        }
+    }
+}
+
+impl Snapshot for LengthCounter {
+    fn save(&self, w: &mut Writer) {
+        w.bool(self.enabled);
+        w.bool(self.halted);
+        w.u8(self.count);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.halted = r.bool()?;
+        self.count = r.u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,27 @@
+// The DAC output is non-linear, so the channels are mixed through the lookup tables from
+// https://wiki.nesdev.com/w/index.php/APU_Mixer
+pub struct Mixer {
+    pulse: [f32; 31],
+    tnd: [f32; 203],
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        let mut pulse = [0.0; 31];
+        for (n, out) in pulse.iter_mut().enumerate().skip(1) {
+            *out = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+        let mut tnd = [0.0; 203];
+        for (n, out) in tnd.iter_mut().enumerate().skip(1) {
+            *out = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+        Self { pulse, tnd }
+    }
+
+    // Produces a sample between 0.0 and 1.0.
+    pub fn mix(&self, pulse_1: u8, pulse_2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+        let pulse = self.pulse[usize::from(pulse_1 + pulse_2)];
+        let tnd = self.tnd[3 * usize::from(triangle) + 2 * usize::from(noise) + usize::from(dmc)];
+        pulse + tnd
+    }
+}
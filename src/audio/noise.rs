@@ -0,0 +1,94 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
+// Timer periods in CPU cycles.
+const PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+
+pub struct Noise {
+    period: u16,
+    timer: u16,
+    shift: u16,
+    short: bool,
+    envelope: Envelope,
+
+    pub counter: LengthCounter,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Self {
+            period: PERIODS[0],
+            timer: 0,
+            shift: 1,
+            short: false,
+            envelope: Envelope::new(),
+
+            counter: LengthCounter::new(),
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            // Mode 1 taps bit 6 instead of bit 1, giving a much shorter, metallic sequence.
+            let tap = if self.short { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) { self.envelope.clock(); }
+
+    pub fn output(&self) -> u8 {
+        if self.shift & 1 != 0 || !self.counter.active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    pub fn active(&self) -> bool { self.counter.active() }
+
+    pub fn set_enabled(&mut self, enable: bool) { self.counter.set_enabled(enable); }
+
+    pub fn write_reg_0(&mut self, val: u8) {
+        self.counter.set_halted(val & 0x20 != 0);
+        self.envelope.write(val);
+    }
+
+    pub fn write_reg_2(&mut self, val: u8) {
+        self.short = val & 0x80 != 0;
+        self.period = PERIODS[usize::from(val & 0x0F)];
+    }
+
+    pub fn write_reg_3(&mut self, val: u8) {
+        self.counter.load(val >> 3);
+        self.envelope.restart();
+    }
+}
+
+impl Snapshot for Noise {
+    fn save(&self, w: &mut Writer) {
+        w.u16(self.period);
+        w.u16(self.timer);
+        w.u16(self.shift);
+        w.bool(self.short);
+        self.envelope.save(w);
+        self.counter.save(w);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.period = r.u16()?;
+        if self.period == 0 {
+            return Err(StateError::Invalid("noise period"));
+        }
+        self.timer = r.u16()?;
+        self.shift = r.u16()?;
+        self.short = r.bool()?;
+        self.envelope.load(r)?;
+        Snapshot::load(&mut self.counter, r)
+    }
+}
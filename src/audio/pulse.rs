@@ -1,14 +1,16 @@
 use std::num::Wrapping;
 
-use super::LENGTH_TABLE;
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+use crate::state::{Reader, Snapshot, StateError, Writer};
 
 pub struct Pulse {
     enabled: bool,
-    sample: f64,
 
     sequence: Sequencer,
     sweep: Sweep,
-    pub counter: Option<Counter>,
+    envelope: Envelope,
+    pub counter: LengthCounter,
 }
 
 pub struct Sweep {
@@ -16,6 +18,9 @@ pub struct Sweep {
     period: u8,
     dir: Direction,
     shift: u8,
+    complement: Complement,
+    divider: u8,
+    reload: bool,
 }
 
 pub struct Sequencer {
@@ -25,47 +30,57 @@ pub struct Sequencer {
     output: u8,
 }
 
-pub struct Counter {
-    running: bool,
-    count: u8,
-}
-
 pub enum Direction {
     Lower,
     Higher,
 }
 
+// Pulse 1 negates its sweep with ones' complement, pulse 2 with two's complement.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Complement {
+    Ones,
+    Twos,
+}
+
 impl Pulse {
-    pub fn new() -> Self {
+    pub fn new(complement: Complement) -> Self {
         Self {
             enabled: false,
-            sample: 0.0,
 
             sequence: Sequencer::new(),
-            sweep: Sweep::new(),
-            counter: None,
+            sweep: Sweep::new(complement),
+            envelope: Envelope::new(),
+            counter: LengthCounter::new(),
         }
     }
 
     pub fn clock(&mut self) {
         if self.enabled {
-            self.sequence.clock(|s| s.rotate_right(1));
+            self.sequence.clock(|s| (s as u8).rotate_right(1) as u32);
         }
+    }
 
-        self.sample = self.sequence.output as f64;
+    pub fn clock_envelope(&mut self) { self.envelope.clock(); }
+
+    pub fn clock_sweep(&mut self) {
+        if let Some(period) = self.sweep.clock(self.sequence.reload.0) {
+            self.sequence.reload.0 = period;
+        }
     }
 
-    pub fn active(&self) -> bool {
-        self.counter.as_ref().map_or(false, |c| c.count > 0) && self.enabled
+    pub fn output(&self) -> u8 {
+        if self.sequence.output == 0 || !self.counter.active() || self.sweep.muting(self.sequence.reload.0) {
+            0
+        } else {
+            self.envelope.output()
+        }
     }
 
+    pub fn active(&self) -> bool { self.counter.active() }
+
     pub fn set_enabled(&mut self, enable: bool) {
         self.enabled = enable;
-        match (enable, self.counter.as_ref()) {
-            (true, None) => self.counter = Some(Counter::new()),
-            (false, _) => self.counter = None,
-            _ => (),
-        };
+        self.counter.set_enabled(enable);
     }
 
     pub fn write_reg_0(&mut self, val: u8) {
@@ -76,11 +91,12 @@ impl Pulse {
             3 => self.sequence.sequence = 0b11111100,
             _ => unreachable!(),
         }
-        if let Some(ref mut c) = self.counter {
-            c.running = val & 0x20 == 0;
-        }
+        self.counter.set_halted(val & 0x20 != 0);
+        self.envelope.write(val);
     }
 
+    pub fn write_reg_1(&mut self, val: u8) { self.sweep.write(val); }
+
     pub fn write_reg_2(&mut self, val: u8) {
         self.sequence.reload.0 = (self.sequence.reload.0 & 0xFF00) | val as u16;
     }
@@ -88,24 +104,62 @@ impl Pulse {
     pub fn write_reg_3(&mut self, val: u8) {
         self.sequence.reload.0 = (self.sequence.reload.0 & 0x00FF) | ((val as u16 & 0x07) << 8);
         self.sequence.reset();
+        self.envelope.restart();
 
-        if let Some(ref mut c) = self.counter {
-            c.count = LENGTH_TABLE[usize::from(val >> 3)];
-        }
+        self.counter.load(val >> 3);
     }
 }
 
 impl Sweep {
-    pub fn new() -> Self {
+    pub fn new(complement: Complement) -> Self {
         Sweep {
             enabled: false,
             period: 0,
             dir: Direction::Lower,
             shift: 0,
+            complement,
+            divider: 0,
+            reload: false,
         }
     }
 
     pub fn set_enabled(&mut self, enable: bool) { self.enabled = enable; }
+
+    pub fn write(&mut self, val: u8) {
+        self.set_enabled(val & 0x80 != 0);
+        self.period = (val >> 4) & 0x07;
+        self.dir = if val & 0x08 != 0 {
+            Direction::Lower
+        } else {
+            Direction::Higher
+        };
+        self.shift = val & 0x07;
+        self.reload = true;
+    }
+
+    fn target(&self, period: u16) -> u16 {
+        let change = period >> self.shift;
+        match (&self.dir, self.complement) {
+            (Direction::Higher, _) => period + change,
+            (Direction::Lower, Complement::Ones) => period.saturating_sub(change + 1),
+            (Direction::Lower, Complement::Twos) => period.saturating_sub(change),
+        }
+    }
+
+    // The channel is silenced even when the sweep itself is disabled.
+    pub fn muting(&self, period: u16) -> bool { period < 8 || self.target(period) > 0x7FF }
+
+    // Clocked by the half frame signal, returning the new timer period when it changes.
+    pub fn clock(&mut self, period: u16) -> Option<u16> {
+        let update = self.divider == 0 && self.enabled && self.shift > 0 && !self.muting(period);
+        if self.divider == 0 || self.reload {
+            self.divider = self.period;
+            self.reload = false;
+        } else {
+            self.divider -= 1;
+        }
+        update.then(|| self.target(period))
+    }
 }
 
 impl Sequencer {
@@ -131,17 +185,61 @@ impl Sequencer {
     }
 }
 
-impl Counter {
-    fn new() -> Self {
-        Self {
-            running: false,
-            count: 0,
-        }
+impl Snapshot for Pulse {
+    fn save(&self, w: &mut Writer) {
+        w.bool(self.enabled);
+
+        self.sequence.save(w);
+        self.sweep.save(w);
+        self.envelope.save(w);
+        self.counter.save(w);
     }
-    pub fn clock(&mut self) -> bool {
-        if self.running && self.count > 0 {
-            self.count -= 1;
-        }
-        self.count > 0
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+
+        self.sequence.load(r)?;
+        self.sweep.load(r)?;
+        self.envelope.load(r)?;
+        Snapshot::load(&mut self.counter, r)
+    }
+}
+
+// The complement is fixed by the channel, so it isn't saved.
+impl Snapshot for Sweep {
+    fn save(&self, w: &mut Writer) {
+        w.bool(self.enabled);
+        w.u8(self.period);
+        w.bool(matches!(self.dir, Direction::Lower));
+        w.u8(self.shift);
+        w.u8(self.divider);
+        w.bool(self.reload);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.period = r.u8()?;
+        self.dir = if r.bool()? { Direction::Lower } else { Direction::Higher };
+        self.shift = r.u8()?;
+        self.divider = r.u8()?;
+        self.reload = r.bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Sequencer {
+    fn save(&self, w: &mut Writer) {
+        w.u32(self.sequence);
+        w.u16(self.timer.0);
+        w.u16(self.reload.0);
+        w.u8(self.output);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.sequence = r.u32()?;
+        self.timer = Wrapping(r.u16()?);
+        self.reload = Wrapping(r.u16()?);
+        self.output = r.u8()?;
+        Ok(())
     }
 }
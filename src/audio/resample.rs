@@ -0,0 +1,62 @@
+use std::collections::vec_deque::{Drain, VecDeque};
+
+use super::filter::{FilterChain, FilterPreset};
+
+pub const CPU_RATE: f64 = 1_789_773.0;
+
+// Samples past this are dropped, oldest first, if the host stops draining the buffer.
+const CAPACITY: usize = 1 << 16;
+
+// Brings the per-cycle mixer output down to the host rate. Each output sample is the average
+// of every input sample in its period. That box filter only roughly band-limits, so some aliasing
+// of high notes gets through.
+pub struct Resampler {
+    pub rate: u32,
+    step: f64,
+    phase: f64,
+    sum: f32,
+    count: u32,
+    filters: FilterChain,
+    samples: VecDeque<f32>,
+}
+
+impl Resampler {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            step: CPU_RATE / f64::from(rate),
+            phase: 0.0,
+            sum: 0.0,
+            count: 0,
+            filters: FilterChain::new(FilterPreset::Nes, rate),
+            samples: VecDeque::with_capacity(CAPACITY),
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: u32) {
+        self.rate = rate;
+        self.step = CPU_RATE / f64::from(rate);
+        self.filters = FilterChain::new(self.filters.preset(), rate);
+    }
+
+    pub fn set_filter(&mut self, preset: FilterPreset) { self.filters = FilterChain::new(preset, self.rate); }
+
+    pub fn push(&mut self, sample: f32) {
+        self.sum += sample;
+        self.count += 1;
+        self.phase += 1.0;
+
+        if self.phase >= self.step {
+            self.phase -= self.step;
+            if self.samples.len() == CAPACITY {
+                self.samples.pop_front();
+            }
+            let sample = self.filters.process(self.sum / self.count as f32);
+            self.samples.push_back(sample);
+            self.sum = 0.0;
+            self.count = 0;
+        }
+    }
+
+    pub fn drain(&mut self) -> Drain<'_, f32> { self.samples.drain(..) }
+}
@@ -0,0 +1,109 @@
+use super::length::LengthCounter;
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+pub struct Triangle {
+    period: u16,
+    timer: u16,
+    step: u8,
+
+    control: bool,
+    reload: bool,
+    linear_reload: u8,
+    linear: u8,
+
+    pub counter: LengthCounter,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Self {
+            period: 0,
+            timer: 0,
+            step: 0,
+
+            control: false,
+            reload: false,
+            linear_reload: 0,
+            linear: 0,
+
+            counter: LengthCounter::new(),
+        }
+    }
+
+    // Unlike the other channels, the triangle's timer runs at the full CPU rate.
+    pub fn clock(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.linear > 0 && self.counter.active() {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_linear(&mut self) {
+        if self.reload {
+            self.linear = self.linear_reload;
+        } else if self.linear > 0 {
+            self.linear -= 1;
+        }
+        if !self.control {
+            self.reload = false;
+        }
+    }
+
+    // Silencing the triangle only stops the sequencer, leaving the output where it was.
+    pub fn output(&self) -> u8 { SEQUENCE[usize::from(self.step)] }
+
+    pub fn active(&self) -> bool { self.counter.active() }
+
+    pub fn set_enabled(&mut self, enable: bool) { self.counter.set_enabled(enable); }
+
+    pub fn write_reg_0(&mut self, val: u8) {
+        self.control = val & 0x80 != 0;
+        self.counter.set_halted(self.control);
+        self.linear_reload = val & 0x7F;
+    }
+
+    pub fn write_reg_2(&mut self, val: u8) { self.period = (self.period & 0xFF00) | val as u16; }
+
+    pub fn write_reg_3(&mut self, val: u8) {
+        self.period = (self.period & 0x00FF) | ((val as u16 & 0x07) << 8);
+        self.counter.load(val >> 3);
+        self.reload = true;
+    }
+}
+
+impl Snapshot for Triangle {
+    fn save(&self, w: &mut Writer) {
+        w.u16(self.period);
+        w.u16(self.timer);
+        w.u8(self.step);
+
+        w.bool(self.control);
+        w.bool(self.reload);
+        w.u8(self.linear_reload);
+        w.u8(self.linear);
+
+        self.counter.save(w);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.period = r.u16()?;
+        self.timer = r.u16()?;
+        self.step = r.u8()? % 32;
+
+        self.control = r.bool()?;
+        self.reload = r.bool()?;
+        self.linear_reload = r.u8()?;
+        self.linear = r.u8()?;
+
+        Snapshot::load(&mut self.counter, r)
+    }
+}
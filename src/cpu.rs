@@ -1,14 +1,19 @@
+use std::cell::Cell;
 use std::fmt::{self, Display, Formatter};
 use std::num::Wrapping;
-
-use genawaiter::stack::Co;
+use std::rc::Rc;
 
 use crate::decode::{Instruction, Opcode};
-use crate::{CycleData, MemoryOp};
+use crate::state::{Reader, Snapshot, StateError, Writer};
+use crate::{Co, CycleData, MemoryOp};
 
 mod instr;
+mod interrupt;
+
+pub use interrupt::{Interrupts, IrqSource};
+use interrupt::{IRQ_VECTOR, NMI_VECTOR};
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct Cpu {
     pub pc: Wrapping<u16>,
     pub stack: Wrapping<u8>,
@@ -16,6 +21,8 @@ pub struct Cpu {
     pub accum: Wrapping<u8>,
     pub x: Wrapping<u8>,
     pub y: Wrapping<u8>,
+    // The I flag as seen by the interrupt poll at the end of the last instruction.
+    masked: bool,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -39,7 +46,7 @@ impl StatusFlags {
     pub const C: u8 = 0x01;
 
     pub fn load(&self) -> Wrapping<u8> {
-        let mut ret = 0x30;
+        let mut ret = 0x20;
         if self.n {
             ret |= Self::N;
         }
@@ -129,6 +136,7 @@ impl Default for Cpu {
             accum: Wrapping(0),
             x: Wrapping(0),
             y: Wrapping(0),
+            masked: true,
         }
     }
 }
@@ -159,261 +167,353 @@ impl Display for Error {
 impl Cpu {
     pub fn set_pc(&mut self, pc: u16) { self.pc.0 = pc; }
 
-    async fn advance(&mut self, co: &Co<'_, MemoryOp, CycleData>) -> Wrapping<u8> {
+    // The registers before the reset sequence that follows power on.
+    pub(crate) fn power_on() -> Self {
+        Self {
+            stack: Wrapping(0),
+            ..Self::default()
+        }
+    }
+
+    async fn advance(&mut self, co: &Co) -> Wrapping<u8> {
         get!(co, self.next_pc())
     }
 
-    pub(crate) async fn run(&mut self, co: Co<'_, MemoryOp, CycleData>) -> Result<(), Error> {
+    // The registers live in `state` between instructions so they can be inspected and modified
+    // whenever the CPU is paused at an instruction boundary.
+    pub(crate) async fn run(
+        state: Rc<Cell<Cpu>>,
+        lines: Rc<Interrupts>,
+        reset: bool,
+        co: Co,
+    ) -> Result<(), Error> {
+        if reset {
+            let mut cpu = state.get();
+            cpu.reset(&co).await;
+            cpu.masked = true;
+            state.set(cpu);
+            co.yield_(MemoryOp::Sync).await;
+        }
         loop {
-            let old_pc = self.pc;
-            let CycleData { val, cycles: _ } = co.yield_(MemoryOp::Read(self.next_pc())).await;
-            let instr = Instruction::decode(val);
-
-            /*
-            println!(
-                "{:04X}: {:?}\t\t[A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}] CYC:{}",
-                old_pc,
-                instr.op_code,
-                self.accum,
-                self.x,
-                self.y,
-                self.status.load().0 & 0xEF,
-                self.stack,
-                cycles + 6,
-            );
-            */
-
-            let addr = self.fetch_address(instr.addr_mode, &co).await;
-
-            match instr.op_code {
-                Opcode::NOP => (),
-
-                Opcode::JMP => {
-                    let addr = addr.unwrap();
-                    if addr == old_pc.0 {
-                        return Ok(());
-                    } /*else if addr < 0x8000 {
-                          panic!("Nonsensical jump target: {:04X}", addr);
-                      }*/
-                    self.pc = Wrapping(addr);
-                }
-                Opcode::JSR => self.jsr(&co).await,
-                Opcode::BRK => self.brk(&co).await,
-                Opcode::RTS => {
-                    self.rts(&co).await;
-                    if self.pc.0 < 2 {
-                        return Ok(());
-                    }
-                }
-                Opcode::RTI => self.rti(&co).await,
-
-                Opcode::PHA => set!(co, (self.push()) <- self.accum),
-                Opcode::PHP => set!(co, (self.push()) <- self.status.load()),
-                Opcode::PLA => self.pla(&co).await,
-                Opcode::PLP => self.plp(&co).await,
-
-                Opcode::CLC => self.status.c = false,
-                Opcode::CLD => self.status.d = false,
-                Opcode::CLI => self.status.i = false,
-                Opcode::CLV => self.status.v = false,
-                Opcode::SEC => self.status.c = true,
-                Opcode::SED => self.status.d = true,
-                Opcode::SEI => self.status.i = true,
-
-                Opcode::TAX => self.transfer(Register::A, Register::X),
-                Opcode::TAY => self.transfer(Register::A, Register::Y),
-                Opcode::TXA => self.transfer(Register::X, Register::A),
-                Opcode::TYA => self.transfer(Register::Y, Register::A),
-                Opcode::TSX => self.transfer(Register::S, Register::X),
-                Opcode::TXS => self.transfer(Register::X, Register::S),
-
-                Opcode::STA => set!(co, (addr.unwrap()) <- self.accum),
-                Opcode::STX => set!(co, (addr.unwrap()) <- self.x),
-                Opcode::STY => set!(co, (addr.unwrap()) <- self.y),
-                Opcode::LDA => self.load(Register::A, addr.unwrap(), &co).await,
-                Opcode::LDX => self.load(Register::X, addr.unwrap(), &co).await,
-                Opcode::LDY => self.load(Register::Y, addr.unwrap(), &co).await,
-
-                Opcode::INC => {
-                    self.mem_op(addr.unwrap(), |x| x + Wrapping(1_u8), &co)
-                        .await;
+            let mut cpu = state.get();
+            let running = cpu.step(&lines, &co).await;
+            state.set(cpu);
+            if !running? {
+                return Ok(());
+            }
+            co.yield_(MemoryOp::Sync).await;
+        }
+    }
+
+    // Executes a single instruction or interrupt sequence, returning false once the CPU halts.
+    async fn step(&mut self, lines: &Interrupts, co: &Co) -> Result<bool, Error> {
+        if lines.take_nmi() {
+            self.interrupt(NMI_VECTOR, lines, co).await;
+            self.masked = true;
+            return Ok(true);
+        }
+        if lines.irq() && !self.masked {
+            self.interrupt(IRQ_VECTOR, lines, co).await;
+            self.masked = true;
+            return Ok(true);
+        }
+
+        let old_pc = self.pc;
+        let CycleData { val, cycles: _ } = co.yield_(MemoryOp::Read(self.next_pc())).await;
+        let instr = Instruction::decode(val);
+
+        /*
+        println!(
+            "{:04X}: {:?}\t\t[A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}] CYC:{}",
+            old_pc,
+            instr.op_code,
+            self.accum,
+            self.x,
+            self.y,
+            self.status.load().0 & 0xEF,
+            self.stack,
+            cycles + 6,
+        );
+        */
+
+        let addr = self.fetch_address(instr.addr_mode, co).await;
+        let old_i = self.status.i;
+
+        match instr.op_code {
+            Opcode::NOP => (),
+
+            Opcode::JMP => {
+                let addr = addr.unwrap();
+                /*if addr < 0x8000 {
+                    panic!("Nonsensical jump target: {:04X}", addr);
+                }*/
+                self.pc = Wrapping(addr);
+                if addr == old_pc.0 && !lines.can_interrupt(self.status.i) {
+                    return Ok(false);
                 }
-                Opcode::DEC => {
-                    self.mem_op(addr.unwrap(), |x| x - Wrapping(1_u8), &co)
-                        .await;
+            }
+            Opcode::JSR => self.jsr(co).await,
+            Opcode::BRK => self.brk(lines, co).await,
+            Opcode::RTS => {
+                self.rts(co).await;
+                if self.pc.0 < 2 {
+                    return Ok(false);
                 }
-                Opcode::INX => self.reg_op(Register::X, |x| x + Wrapping(1_u8)),
-                Opcode::INY => self.reg_op(Register::Y, |x| x + Wrapping(1_u8)),
-                Opcode::DEX => self.reg_op(Register::X, |x| x - Wrapping(1_u8)),
-                Opcode::DEY => self.reg_op(Register::Y, |x| x - Wrapping(1_u8)),
+            }
+            Opcode::RTI => self.rti(co).await,
+
+            Opcode::PHA => set!(co, (self.push()) <- self.accum),
+            Opcode::PHP => set!(co, (self.push()) <- StatusFlags { b: true, ..self.status }.load()),
+            Opcode::PLA => self.pla(co).await,
+            Opcode::PLP => self.plp(co).await,
+
+            Opcode::CLC => self.status.c = false,
+            Opcode::CLD => self.status.d = false,
+            Opcode::CLI => self.status.i = false,
+            Opcode::CLV => self.status.v = false,
+            Opcode::SEC => self.status.c = true,
+            Opcode::SED => self.status.d = true,
+            Opcode::SEI => self.status.i = true,
+
+            Opcode::TAX => self.transfer(Register::A, Register::X),
+            Opcode::TAY => self.transfer(Register::A, Register::Y),
+            Opcode::TXA => self.transfer(Register::X, Register::A),
+            Opcode::TYA => self.transfer(Register::Y, Register::A),
+            Opcode::TSX => self.transfer(Register::S, Register::X),
+            Opcode::TXS => self.transfer(Register::X, Register::S),
+
+            Opcode::STA => set!(co, (addr.unwrap()) <- self.accum),
+            Opcode::STX => set!(co, (addr.unwrap()) <- self.x),
+            Opcode::STY => set!(co, (addr.unwrap()) <- self.y),
+            Opcode::LDA => self.load(Register::A, addr.unwrap(), co).await,
+            Opcode::LDX => self.load(Register::X, addr.unwrap(), co).await,
+            Opcode::LDY => self.load(Register::Y, addr.unwrap(), co).await,
+
+            Opcode::INC => {
+                self.mem_op(addr.unwrap(), |x| x + Wrapping(1_u8), co)
+                    .await;
+            }
+            Opcode::DEC => {
+                self.mem_op(addr.unwrap(), |x| x - Wrapping(1_u8), co)
+                    .await;
+            }
+            Opcode::INX => self.reg_op(Register::X, |x| x + Wrapping(1_u8)),
+            Opcode::INY => self.reg_op(Register::Y, |x| x + Wrapping(1_u8)),
+            Opcode::DEX => self.reg_op(Register::X, |x| x - Wrapping(1_u8)),
+            Opcode::DEY => self.reg_op(Register::Y, |x| x - Wrapping(1_u8)),
 
-                Opcode::EOR => self.bin_op(get!(co, addr.unwrap()), |x, y| x ^ y),
-                Opcode::ORA => self.bin_op(get!(co, addr.unwrap()), |x, y| x | y),
-                Opcode::AND => self.bin_op(get!(co, addr.unwrap()), |x, y| x & y),
+            Opcode::EOR => self.bin_op(get!(co, addr.unwrap()), |x, y| x ^ y),
+            Opcode::ORA => self.bin_op(get!(co, addr.unwrap()), |x, y| x | y),
+            Opcode::AND => self.bin_op(get!(co, addr.unwrap()), |x, y| x & y),
 
-                Opcode::ADC => self.adc(get!(co, addr.unwrap()).0),
-                Opcode::SBC => self.adc(!get!(co, addr.unwrap()).0),
-                Opcode::BIT => self.bit_test(get!(co, addr.unwrap()).0),
+            Opcode::ADC => self.adc(get!(co, addr.unwrap()).0),
+            Opcode::SBC => self.adc(!get!(co, addr.unwrap()).0),
+            Opcode::BIT => self.bit_test(get!(co, addr.unwrap()).0),
 
-                Opcode::CMP => {
-                    self.compare(self.accum, get!(co, addr.unwrap()));
-                }
-                Opcode::CPX => {
-                    self.compare(self.x, get!(co, addr.unwrap()));
-                }
-                Opcode::CPY => {
-                    self.compare(self.y, get!(co, addr.unwrap()));
-                }
+            Opcode::CMP => {
+                self.compare(self.accum, get!(co, addr.unwrap()));
+            }
+            Opcode::CPX => {
+                self.compare(self.x, get!(co, addr.unwrap()));
+            }
+            Opcode::CPY => {
+                self.compare(self.y, get!(co, addr.unwrap()));
+            }
 
-                Opcode::BCS => {
-                    if self.branch(|cpu| cpu.status.c, &co).await {
-                        return Ok(());
-                    }
+            Opcode::BCS => {
+                if self.branch(|cpu| cpu.status.c, co).await
+                    && !lines.can_interrupt(self.status.i)
+                {
+                    return Ok(false);
                 }
-                Opcode::BEQ => {
-                    if self.branch(|cpu| cpu.status.z, &co).await {
-                        return Ok(());
-                    }
+            }
+            Opcode::BEQ => {
+                if self.branch(|cpu| cpu.status.z, co).await
+                    && !lines.can_interrupt(self.status.i)
+                {
+                    return Ok(false);
                 }
-                Opcode::BVS => {
-                    if self.branch(|cpu| cpu.status.v, &co).await {
-                        return Ok(());
-                    }
+            }
+            Opcode::BVS => {
+                if self.branch(|cpu| cpu.status.v, co).await
+                    && !lines.can_interrupt(self.status.i)
+                {
+                    return Ok(false);
                 }
-                Opcode::BMI => {
-                    if self.branch(|cpu| cpu.status.n, &co).await {
-                        return Ok(());
-                    }
+            }
+            Opcode::BMI => {
+                if self.branch(|cpu| cpu.status.n, co).await
+                    && !lines.can_interrupt(self.status.i)
+                {
+                    return Ok(false);
                 }
-                Opcode::BCC => {
-                    if self.branch(|cpu| !cpu.status.c, &co).await {
-                        return Ok(());
-                    }
+            }
+            Opcode::BCC => {
+                if self.branch(|cpu| !cpu.status.c, co).await
+                    && !lines.can_interrupt(self.status.i)
+                {
+                    return Ok(false);
                 }
-                Opcode::BNE => {
-                    if self.branch(|cpu| !cpu.status.z, &co).await {
-                        return Ok(());
-                    }
+            }
+            Opcode::BNE => {
+                if self.branch(|cpu| !cpu.status.z, co).await
+                    && !lines.can_interrupt(self.status.i)
+                {
+                    return Ok(false);
                 }
-                Opcode::BVC => {
-                    if self.branch(|cpu| !cpu.status.v, &co).await {
-                        return Ok(());
-                    }
+            }
+            Opcode::BVC => {
+                if self.branch(|cpu| !cpu.status.v, co).await
+                    && !lines.can_interrupt(self.status.i)
+                {
+                    return Ok(false);
                 }
-                Opcode::BPL => {
-                    if self.branch(|cpu| !cpu.status.n, &co).await {
-                        return Ok(());
-                    }
+            }
+            Opcode::BPL => {
+                if self.branch(|cpu| !cpu.status.n, co).await
+                    && !lines.can_interrupt(self.status.i)
+                {
+                    return Ok(false);
                 }
+            }
 
-                Opcode::ASL => {
-                    self.shift_op(addr, |x, _| (x << 1, (x.0 as i8) < 0), &co)
-                        .await;
-                }
-                Opcode::LSR => {
-                    self.shift_op(addr, |x, _| (x >> 1, x.0 & 1 != 0), &co)
-                        .await;
-                }
-                Opcode::ROL => {
-                    self.shift_op(
+            Opcode::ASL => {
+                self.shift_op(addr, |x, _| (x << 1, (x.0 as i8) < 0), co)
+                    .await;
+            }
+            Opcode::LSR => {
+                self.shift_op(addr, |x, _| (x >> 1, x.0 & 1 != 0), co)
+                    .await;
+            }
+            Opcode::ROL => {
+                self.shift_op(
+                    addr,
+                    |x, c| (x << 1 | Wrapping(c as u8), (x.0 as i8) < 0),
+                    co,
+                )
+                .await;
+            }
+            Opcode::ROR => {
+                self.shift_op(
+                    addr,
+                    |x, c| (x >> 1 | Wrapping(c as u8) << 7, x.0 & 1 != 0),
+                    co,
+                )
+                .await;
+            }
+
+            Opcode::NOPConsume => {
+                get!(co, addr.unwrap());
+            }
+            Opcode::LAX => {
+                self.load(Register::A, addr.unwrap(), co).await;
+                self.transfer(Register::A, Register::X);
+            }
+            Opcode::SAX => set!(co, (addr.unwrap()) <- self.accum & self.x),
+            Opcode::DCP => {
+                let val = self
+                    .mem_op(addr.unwrap(), |x| x - Wrapping(1_u8), co)
+                    .await;
+                self.compare(self.accum, val);
+            }
+            Opcode::ISB => {
+                let val = self
+                    .mem_op(addr.unwrap(), |x| x + Wrapping(1_u8), co)
+                    .await;
+                self.adc(!val.0);
+            }
+            Opcode::ANC => {
+                self.bin_op(get!(co, addr.unwrap()), |x, y| x & y);
+                self.status.c = self.status.n;
+            }
+            Opcode::ALR => {
+                self.bin_op(get!(co, addr.unwrap()), |x, y| x & y);
+                self.shift_op(None, |x, _| (x >> 1, x.0 & 1 != 0), co)
+                    .await;
+            }
+            Opcode::ARR => {
+                self.bin_op(get!(co, addr.unwrap()), |x, y| x & y);
+                let val = self
+                    .shift_op(
+                        None,
+                        |x, c| (x >> 1 | Wrapping(c as u8) << 7, x.0 & (1 << 7) != 0),
+                        co,
+                    )
+                    .await;
+                self.status.v = ((val >> 6).0 & 1) != ((val >> 5).0 & 1);
+            }
+            Opcode::AXS => self.x = self.compare(self.accum & self.x, get!(co, addr.unwrap())),
+            Opcode::SLO => {
+                let val = self
+                    .shift_op(addr, |x, _| (x << 1, (x.0 as i8) < 0), co)
+                    .await;
+                self.bin_op(val, |x, y| x | y);
+            }
+            Opcode::SRE => {
+                let val = self
+                    .shift_op(addr, |x, _| (x >> 1, x.0 & 1 != 0), co)
+                    .await;
+                self.bin_op(val, |x, y| x ^ y);
+            }
+            Opcode::RLA => {
+                let val = self
+                    .shift_op(
                         addr,
                         |x, c| (x << 1 | Wrapping(c as u8), (x.0 as i8) < 0),
-                        &co,
+                        co,
                     )
                     .await;
-                }
-                Opcode::ROR => {
-                    self.shift_op(
+                self.bin_op(val, |x, y| x & y);
+            }
+            Opcode::RRA => {
+                let val = self
+                    .shift_op(
                         addr,
                         |x, c| (x >> 1 | Wrapping(c as u8) << 7, x.0 & 1 != 0),
-                        &co,
+                        co,
                     )
                     .await;
-                }
-
-                Opcode::NOPConsume => {
-                    get!(co, addr.unwrap());
-                }
-                Opcode::LAX => {
-                    self.load(Register::A, addr.unwrap(), &co).await;
-                    self.transfer(Register::A, Register::X);
-                }
-                Opcode::SAX => set!(co, (addr.unwrap()) <- self.accum & self.x),
-                Opcode::DCP => {
-                    let val = self
-                        .mem_op(addr.unwrap(), |x| x - Wrapping(1_u8), &co)
-                        .await;
-                    self.compare(self.accum, val);
-                }
-                Opcode::ISB => {
-                    let val = self
-                        .mem_op(addr.unwrap(), |x| x + Wrapping(1_u8), &co)
-                        .await;
-                    self.adc(!val.0);
-                }
-                Opcode::ANC => {
-                    self.bin_op(get!(co, addr.unwrap()), |x, y| x & y);
-                    self.status.c = self.status.n;
-                }
-                Opcode::ALR => {
-                    self.bin_op(get!(co, addr.unwrap()), |x, y| x & y);
-                    self.shift_op(None, |x, _| (x >> 1, x.0 & 1 != 0), &co)
-                        .await;
-                }
-                Opcode::ARR => {
-                    self.bin_op(get!(co, addr.unwrap()), |x, y| x & y);
-                    let val = self
-                        .shift_op(
-                            None,
-                            |x, c| (x >> 1 | Wrapping(c as u8) << 7, x.0 & (1 << 7) != 0),
-                            &co,
-                        )
-                        .await;
-                    self.status.v = ((val >> 6).0 & 1) != ((val >> 5).0 & 1);
-                }
-                Opcode::AXS => self.x = self.compare(self.accum & self.x, get!(co, addr.unwrap())),
-                Opcode::SLO => {
-                    let val = self
-                        .shift_op(addr, |x, _| (x << 1, (x.0 as i8) < 0), &co)
-                        .await;
-                    self.bin_op(val, |x, y| x | y);
-                }
-                Opcode::SRE => {
-                    let val = self
-                        .shift_op(addr, |x, _| (x >> 1, x.0 & 1 != 0), &co)
-                        .await;
-                    self.bin_op(val, |x, y| x ^ y);
-                }
-                Opcode::RLA => {
-                    let val = self
-                        .shift_op(
-                            addr,
-                            |x, c| (x << 1 | Wrapping(c as u8), (x.0 as i8) < 0),
-                            &co,
-                        )
-                        .await;
-                    self.bin_op(val, |x, y| x & y);
-                }
-                Opcode::RRA => {
-                    let val = self
-                        .shift_op(
-                            addr,
-                            |x, c| (x >> 1 | Wrapping(c as u8) << 7, x.0 & 1 != 0),
-                            &co,
-                        )
-                        .await;
-                    self.adc(val.0);
-                }
-                Opcode::SXA => self.sra(Register::X, &co).await,
-                Opcode::SYA => self.sra(Register::Y, &co).await,
-                Opcode::XAA => self.xaa(get!(co, addr.unwrap())),
-                Opcode::AHX => {
-                    set!(co, (addr.unwrap()) <- Wrapping(((addr.unwrap() >> 8) + 1) as u8) & self.accum & self.x)
-                }
-                Opcode::TAS => self.tas(addr.unwrap(), &co).await,
-                Opcode::LAS => self.las(get!(co, addr.unwrap())),
-                _ => return Err(Error::UnknownInstr(instr, self.pc - Wrapping(1))),
+                self.adc(val.0);
             }
+            Opcode::SXA => self.sra(Register::X, co).await,
+            Opcode::SYA => self.sra(Register::Y, co).await,
+            Opcode::XAA => self.xaa(get!(co, addr.unwrap())),
+            Opcode::AHX => {
+                set!(co, (addr.unwrap()) <- Wrapping(((addr.unwrap() >> 8) + 1) as u8) & self.accum & self.x)
+            }
+            Opcode::TAS => self.tas(addr.unwrap(), co).await,
+            Opcode::LAS => self.las(get!(co, addr.unwrap())),
+            _ => return Err(Error::UnknownInstr(instr, self.pc - Wrapping(1))),
         }
+
+        // CLI, SEI and PLP change I after the poll, so their effect is delayed by an instruction.
+        self.masked = match instr.op_code {
+            Opcode::CLI | Opcode::SEI | Opcode::PLP => old_i,
+            _ => self.status.i,
+        };
+        Ok(true)
+    }
+}
+
+impl Snapshot for Cpu {
+    fn save(&self, w: &mut Writer) {
+        w.u16(self.pc.0);
+        w.u8(self.stack.0);
+        w.u8(self.status.into());
+        w.u8(self.accum.0);
+        w.u8(self.x.0);
+        w.u8(self.y.0);
+        w.bool(self.masked);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.pc = Wrapping(r.u16()?);
+        self.stack = Wrapping(r.u8()?);
+        self.status = r.u8()?.into();
+        self.accum = Wrapping(r.u8()?);
+        self.x = Wrapping(r.u8()?);
+        self.y = Wrapping(r.u8()?);
+        self.masked = r.bool()?;
+        Ok(())
     }
 }
@@ -1,5 +1,6 @@
 use std::num::Wrapping;
 
+use super::interrupt::{Interrupts, IRQ_VECTOR, NMI_VECTOR, RESET_VECTOR};
 use super::{Cpu, Register, StatusFlags};
 use crate::decode::{AddressMode, Fix};
 use crate::Co;
@@ -19,7 +20,7 @@ fn from_le_bytes([low, high]: [Wrapping<u8>; 2]) -> Wrapping<u16> {
 }
 
 impl Cpu {
-    async fn fetch_add(&mut self, src: Source, offset: u8, fix: Fix, co: &Co<'_>) -> u16 {
+    async fn fetch_add(&mut self, src: Source, offset: u8, fix: Fix, co: &Co) -> u16 {
         let [low, high] = match src {
             Source::Pc => {
                 let low = self.advance(co).await.0;
@@ -44,7 +45,7 @@ impl Cpu {
         u16::from_le_bytes([effective_low, effective_high])
     }
 
-    pub(crate) async fn fetch_address(&mut self, mode: AddressMode, co: &Co<'_>) -> Option<u16> {
+    pub(crate) async fn fetch_address(&mut self, mode: AddressMode, co: &Co) -> Option<u16> {
         match mode {
             AddressMode::Manual => None,
             AddressMode::Implicit => {
@@ -106,7 +107,7 @@ impl Cpu {
         }
     }
 
-    pub(super) async fn load(&mut self, reg: Register, addr: u16, co: &Co<'_>) {
+    pub(super) async fn load(&mut self, reg: Register, addr: u16, co: &Co) {
         let val = get!(co, addr);
         match reg {
             Register::A => self.accum = val,
@@ -122,7 +123,7 @@ impl Cpu {
         &mut self,
         addr: u16,
         op: impl FnOnce(Wrapping<u8>) -> Wrapping<u8>,
-        co: &Co<'_>,
+        co: &Co,
     ) -> Wrapping<u8> {
         let val = get!(co, addr);
         set!(co, addr <- val);
@@ -160,7 +161,7 @@ impl Cpu {
         &mut self,
         arg: Option<u16>,
         op: impl FnOnce(Wrapping<u8>, bool) -> (Wrapping<u8>, bool),
-        co: &Co<'_>,
+        co: &Co,
     ) -> Wrapping<u8> {
         let (val, carry) = match arg {
             Some(addr) => {
@@ -183,17 +184,44 @@ impl Cpu {
         val
     }
 
-    pub(super) async fn brk(&mut self, co: &Co<'_>) {
+    pub(super) async fn brk(&mut self, lines: &Interrupts, co: &Co) {
         self.pc += Wrapping(1);
         let [pcl, pch] = to_le_bytes(self.pc);
         set!(co, (self.push()) <- pch);
         set!(co, (self.push()) <- pcl);
         set!(co, (self.push()) <- StatusFlags{ b: true, ..self.status}.load());
-        self.set_pcl(get!(co, 0xFFFE));
-        self.set_pch(get!(co, 0xFFFF));
+        // An NMI arriving before the vector fetch hijacks the BRK.
+        let vector = if lines.take_nmi() { NMI_VECTOR } else { IRQ_VECTOR };
+        self.set_pcl(get!(co, vector));
+        self.set_pch(get!(co, vector + 1));
         self.status.i = true;
     }
 
+    pub(super) async fn interrupt(&mut self, vector: u16, lines: &Interrupts, co: &Co) {
+        get!(co, self.get_pc());
+        get!(co, self.get_pc());
+        let [pcl, pch] = to_le_bytes(self.pc);
+        set!(co, (self.push()) <- pch);
+        set!(co, (self.push()) <- pcl);
+        set!(co, (self.push()) <- StatusFlags{ b: false, ..self.status}.load());
+        let vector = if vector == IRQ_VECTOR && lines.take_nmi() { NMI_VECTOR } else { vector };
+        self.status.i = true;
+        self.set_pcl(get!(co, vector));
+        self.set_pch(get!(co, vector + 1));
+    }
+
+    // Runs like an interrupt, except the stack writes are turned into reads.
+    pub(super) async fn reset(&mut self, co: &Co) {
+        get!(co, self.get_pc());
+        get!(co, self.get_pc());
+        for _ in 0..3 {
+            get!(co, self.push());
+        }
+        self.status.i = true;
+        self.set_pcl(get!(co, RESET_VECTOR));
+        self.set_pch(get!(co, RESET_VECTOR + 1));
+    }
+
     pub(super) fn transfer(&mut self, src: Register, dst: Register) {
         let val = match src {
             Register::A => self.accum,
@@ -215,7 +243,7 @@ impl Cpu {
         }
     }
 
-    pub(super) async fn jsr(&mut self, co: &Co<'_>) {
+    pub(super) async fn jsr(&mut self, co: &Co) {
         let new_pcl = self.advance(co).await;
         let [pcl, pch] = to_le_bytes(self.pc);
         get!(co, self.peek());
@@ -224,7 +252,7 @@ impl Cpu {
         self.pc = from_le_bytes([new_pcl, self.advance(co).await]);
     }
 
-    pub(super) async fn rts(&mut self, co: &Co<'_>) {
+    pub(super) async fn rts(&mut self, co: &Co) {
         get!(co, self.pop());
         let pcl = self.pop();
         self.set_pcl(get!(co, pcl));
@@ -233,7 +261,7 @@ impl Cpu {
         self.advance(co).await;
     }
 
-    pub(super) async fn rti(&mut self, co: &Co<'_>) {
+    pub(super) async fn rti(&mut self, co: &Co) {
         get!(co, self.pop());
         self.status = StatusFlags::store(get!(co, self.pop()));
         let pcl = get!(co, self.pop());
@@ -241,7 +269,7 @@ impl Cpu {
         self.pc = from_le_bytes([pcl, pch]);
     }
 
-    pub(super) async fn pla(&mut self, co: &Co<'_>) {
+    pub(super) async fn pla(&mut self, co: &Co) {
         get!(co, self.pop());
         self.accum = get!(co, self.peek());
 
@@ -249,20 +277,16 @@ impl Cpu {
         self.status.n = (self.accum.0 as i8) < 0;
     }
 
-    pub(super) async fn plp(&mut self, co: &Co<'_>) {
+    pub(super) async fn plp(&mut self, co: &Co) {
         get!(co, self.pop());
         self.status = StatusFlags::store(get!(co, self.peek()));
     }
 
-    pub(super) async fn branch(&mut self, test: impl FnOnce(&Cpu) -> bool, co: &Co<'_>) -> bool {
+    pub(super) async fn branch(&mut self, test: impl FnOnce(&Cpu) -> bool, co: &Co) -> bool {
         let offset = self.advance(co).await;
 
         let [old_pcl, old_pch] = to_le_bytes(self.pc);
         if test(&*self) {
-            if offset.0 as i8 == -2 {
-                return true;
-            }
-
             get!(co, self.get_pc());
             let pcl = old_pcl + offset;
             if (offset.0 as i8) < 0 {
@@ -278,6 +302,7 @@ impl Cpu {
                     self.set_pch(old_pch + Wrapping(1));
                 }
             };
+            return offset.0 as i8 == -2;
         }
 
         false
@@ -313,7 +338,7 @@ impl Cpu {
         diff
     }
 
-    pub(super) async fn sra(&mut self, reg: Register, co: &Co<'_>) {
+    pub(super) async fn sra(&mut self, reg: Register, co: &Co) {
         let low = self.advance(co).await.0;
         let high = self.advance(co).await;
 
@@ -341,7 +366,7 @@ impl Cpu {
         self.status.n = (val.0 as i8).is_negative();
     }
 
-    pub(super) async fn tas(&mut self, addr: u16, co: &Co<'_>) {
+    pub(super) async fn tas(&mut self, addr: u16, co: &Co) {
         let val = Wrapping(((addr >> 8) + 1) as u8) & self.accum & self.x;
         set!(co, addr <- val);
         self.stack = val;
@@ -0,0 +1,80 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
+pub const NMI_VECTOR: u16 = 0xFFFA;
+pub const RESET_VECTOR: u16 = 0xFFFC;
+pub const IRQ_VECTOR: u16 = 0xFFFE;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IrqSource {
+    FrameCounter = 0,
+    Dmc = 1,
+    Mapper = 2,
+}
+
+#[derive(Debug, Default)]
+pub struct Interrupts {
+    nmi: Cell<bool>,
+    nmi_enabled: Cell<bool>,
+    irq: Cell<u8>,
+}
+
+impl Interrupts {
+    pub fn new() -> Self { Self::default() }
+
+    // /NMI is edge triggered, so a single pulse stays latched until the CPU services it.
+    pub fn raise_nmi(&self) { self.nmi.set(true); }
+
+    pub fn take_nmi(&self) -> bool { self.nmi.take() }
+
+    pub fn set_nmi_enabled(&self, enabled: bool) { self.nmi_enabled.set(enabled); }
+
+    pub fn clear(&self) {
+        self.nmi.set(false);
+        self.nmi_enabled.set(false);
+        self.irq.set(0);
+    }
+
+    // /IRQ is level triggered and wired-OR, so it stays asserted until every source acknowledges.
+    pub fn raise_irq(&self, src: IrqSource) { self.irq.set(self.irq.get() | src.mask()); }
+
+    pub fn ack_irq(&self, src: IrqSource) { self.irq.set(self.irq.get() & !src.mask()); }
+
+    pub fn set_irq(&self, src: IrqSource, level: bool) {
+        if level {
+            self.raise_irq(src);
+        } else {
+            self.ack_irq(src);
+        }
+    }
+
+    pub fn is_raised(&self, src: IrqSource) -> bool { self.irq.get() & src.mask() != 0 }
+
+    pub fn irq(&self) -> bool { self.irq.get() != 0 }
+
+    pub fn can_interrupt(&self, masked: bool) -> bool {
+        self.nmi.get() || self.nmi_enabled.get() || (!masked && self.irq())
+    }
+}
+
+impl IrqSource {
+    fn mask(self) -> u8 { 1 << self as u8 }
+}
+
+// Shared with the CPU and APU, so it's restored in place.
+impl Snapshot for Rc<Interrupts> {
+    fn save(&self, w: &mut Writer) {
+        w.bool(self.nmi.get());
+        w.bool(self.nmi_enabled.get());
+        w.u8(self.irq.get());
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.nmi.set(r.bool()?);
+        self.nmi_enabled.set(r.bool()?);
+        self.irq.set(r.u8()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use crate::MemoryOp;
+
+pub struct OamDma {
+    page: u8,
+    step: u16,
+    halted: bool,
+}
+
+impl OamDma {
+    pub const fn new(page: u8) -> Self {
+        OamDma {
+            page,
+            step: 0,
+            halted: false,
+        }
+    }
+
+    // One halt cycle, one more if needed to line up with a get cycle, then 256 get/put pairs.
+    pub(crate) fn next(&mut self, cycle: u64, val: u8) -> Option<MemoryOp> {
+        if !self.halted {
+            self.halted = true;
+            return Some(MemoryOp::Idle);
+        }
+        if self.step >= 512 {
+            return None;
+        }
+        if self.step == 0 && cycle % 2 == 1 {
+            return Some(MemoryOp::Idle);
+        }
+
+        let addr = u16::from_le_bytes([(self.step / 2) as u8, self.page]);
+        let op = if self.step % 2 == 0 {
+            MemoryOp::Read(addr)
+        } else {
+            MemoryOp::Write(0x2004, val)
+        };
+        self.step += 1;
+        Some(op)
+    }
+}
+
+pub struct DmcDma {
+    addr: u16,
+    step: u8,
+}
+
+impl DmcDma {
+    pub const fn new(addr: u16) -> Self { DmcDma { addr, step: 0 } }
+
+    // Halt and dummy cycles, one more if needed to line up with a get cycle, then the sample read.
+    pub(crate) fn next(&mut self, cycle: u64) -> Option<MemoryOp> {
+        let op = match self.step {
+            0 | 1 => MemoryOp::Idle,
+            2 if cycle % 2 == 1 => return Some(MemoryOp::Idle),
+            2 => MemoryOp::Read(self.addr),
+            _ => return None,
+        };
+        self.step += 1;
+        Some(op)
+    }
+}
@@ -1,7 +1,6 @@
-use std::convert::TryFrom;
 use std::fmt::{self, Display, Formatter};
 
-use crate::memory::PRG_BANK_SIZE;
+use crate::memory::{CHR_BANK_SIZE, PRG_BANK_SIZE};
 
 pub struct Rom<'a> {
     pub header: Header,
@@ -16,21 +15,43 @@ pub struct Header {
     flags6: Flags6,
     flags7: Flags7,
     region: Region,
-    mapper: Mapper,
+    mapper: u16,
+    submapper: u8,
     version: Version,
+    // Sizes in bytes, with NV being battery backed.
+    prg_ram: u32,
+    prg_nvram: u32,
+    chr_ram: u32,
+    chr_nvram: u32,
+    console: Console,
+    misc_roms: u8,
+    expansion: u8,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mirroring {
     Horizontal,
     Vertical,
-    Ignore,
+    // The board has its own nametable RAM, so none are mirrored.
+    FourScreen,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Region {
     NTSC,
     PAL,
+    // Works on either NTSC or PAL consoles.
+    Multi,
+    Dendy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    Nes,
+    // The PPU and hardware types are numbered as in the NES 2.0 header.
+    VsSystem { ppu: u8, hardware: u8 },
+    PlayChoice,
+    Extended(u8),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -46,53 +67,84 @@ pub struct Flags7 {
     play_choice: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Version {
     Archaic,
     INes,
     Nes2_0,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum Mapper {
-    NROM = 0,
-    MMC1 = 1,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomError {
+    Magic,
+    TruncatedHeader,
+    TruncatedTrainer,
+    TruncatedPrg,
+    TruncatedChr,
+    // PRG ROM must be at least one 16KiB bank, and CHR ROM whole 8KiB banks.
+    PrgSize,
+    ChrSize,
+    Mapper(UnknownMapper),
+    // The header is valid, but describes something that can't be loaded.
+    Unsupported(Version),
 }
 
 impl<'a> Rom<'a> {
-    pub fn parse(rom: &'a [u8]) -> Option<Self> {
+    pub fn parse(rom: &'a [u8]) -> Result<Self, RomError> {
         let header = Header::parse(rom)?;
+        if header.prg_size == 0 || header.prg_size as usize % PRG_BANK_SIZE != 0 {
+            return Err(RomError::PrgSize);
+        }
+        if header.chr_size as usize % CHR_BANK_SIZE != 0 {
+            return Err(RomError::ChrSize);
+        }
 
         let rom_start = 16 + if header.flags6.trainer { 512 } else { 0 };
-        let rom = &rom[rom_start..];
-        let (prg, rom) = rom.split_at(header.prg_size as usize);
-        let chr = &rom[..header.chr_size as usize];
-        Some(Self { header, prg, chr })
+        let rom = rom.get(rom_start..).ok_or(RomError::TruncatedTrainer)?;
+        let prg = rom.get(..header.prg_size as usize).ok_or(RomError::TruncatedPrg)?;
+        let rom = &rom[prg.len()..];
+        let chr = rom.get(..header.chr_size as usize).ok_or(RomError::TruncatedChr)?;
+        Ok(Self { header, prg, chr })
     }
 
     pub fn version(&self) -> Version { self.header.version }
 
     pub fn is_play_choice(&self) -> bool { self.header.flags7.play_choice }
 
-    pub fn mapper(&self) -> Mapper { self.header.mapper }
+    pub fn mapper(&self) -> u16 { self.header.mapper }
+
+    // Always 0 outside of NES 2.0.
+    pub fn submapper(&self) -> u8 { self.header.submapper }
+
+    pub fn region(&self) -> Region { self.header.region }
+
+    pub fn console(&self) -> Console { self.header.console }
+
+    pub fn prg_ram_size(&self) -> u32 { self.header.prg_ram }
+
+    pub fn prg_nvram_size(&self) -> u32 { self.header.prg_nvram }
+
+    pub fn chr_ram_size(&self) -> u32 { self.header.chr_ram }
+
+    pub fn chr_nvram_size(&self) -> u32 { self.header.chr_nvram }
+
+    pub fn misc_roms(&self) -> u8 { self.header.misc_roms }
+
+    // The NES 2.0 default expansion device number, 0 when unspecified.
+    pub fn expansion_device(&self) -> u8 { self.header.expansion }
 
     pub fn mirror(&self) -> Mirroring { self.header.flags6.mirror }
+
+    // Whether the work RAM is battery backed, and so should be kept between runs.
+    pub fn has_battery(&self) -> bool { self.header.flags6.battery }
 }
 
 impl Header {
-    pub fn parse(rom: &[u8]) -> Option<Header> {
-        if &rom.get(0..4)? != b"NES\x1a" {
-            return None;
+    pub fn parse(rom: &[u8]) -> Result<Header, RomError> {
+        if rom.get(0..4) != Some(&b"NES\x1a"[..]) {
+            return Err(RomError::Magic);
         }
-        let prg_size = rom[4] as u32 * PRG_BANK_SIZE as u32;
-        let chr_size = rom[5] as u32 * 0x2000;
-        let flags6 = rom[6].into();
-        let flags7 = rom[7].into();
-        let region = if rom[9] & 1 == 0 {
-            Region::NTSC
-        } else {
-            Region::PAL
-        };
+        let rom = rom.get(0..16).ok_or(RomError::TruncatedHeader)?;
 
         let version = if rom[7] & 0x0C == 0x08 {
             Version::Nes2_0
@@ -102,33 +154,116 @@ impl Header {
             Version::Archaic
         };
 
-        eprintln!(
-            "prg: {} [{:#X}] ({} banks), chr: {} [{:#X}] ({} banks)",
-            prg_size, prg_size, rom[4], chr_size, chr_size, rom[5]
-        );
+        match version {
+            Version::Nes2_0 => Self::parse_nes2(rom),
+            Version::INes | Version::Archaic => Self::parse_ines(rom, version),
+        }
+    }
 
-        let mapper = match version {
-            Version::Archaic => unimplemented!("Archaic iNES ROM"),
-            Version::INes => Mapper::try_from(rom[6] >> 4 | rom[7] & 0xF0).unwrap(),
-            Version::Nes2_0 => todo!("NES 2.0 ROM"),
+    fn parse_ines(rom: &[u8], version: Version) -> Result<Header, RomError> {
+        let flags6: Flags6 = rom[6].into();
+        // Archaic headers often have bytes 7-15 filled with junk, like a ripper's name.
+        let flags7 = if version == Version::INes { rom[7] } else { 0 };
+        let (region, prg_ram) = match version {
+            Version::INes => (
+                if rom[9] & 1 == 0 { Region::NTSC } else { Region::PAL },
+                // 0 means 8KiB, for compatibility.
+                u32::from(rom[8].max(1)) * 0x2000,
+            ),
+            _ => (Region::NTSC, 0x2000),
         };
+        let chr_size = rom[5] as u32 * 0x2000;
+        let (prg_ram, prg_nvram) = if flags6.battery { (0, prg_ram) } else { (prg_ram, 0) };
 
-        Some(Self {
-            prg_size,
+        Ok(Header {
+            prg_size: rom[4] as u32 * PRG_BANK_SIZE as u32,
             chr_size,
             flags6,
-            flags7,
+            flags7: flags7.into(),
             region,
+            mapper: u16::from(rom[6] >> 4 | flags7 & 0xF0),
+            submapper: 0,
             version,
+            prg_ram,
+            prg_nvram,
+            chr_ram: if chr_size == 0 { 0x2000 } else { 0 },
+            chr_nvram: 0,
+            console: Console::from_flags7(flags7, 0),
+            misc_roms: 0,
+            expansion: 0,
+        })
+    }
+
+    fn parse_nes2(rom: &[u8]) -> Result<Header, RomError> {
+        let mapper = u16::from(rom[6] >> 4) | u16::from(rom[7] & 0xF0) | u16::from(rom[8] & 0x0F) << 8;
+        let region = match rom[12] & 0x03 {
+            0 => Region::NTSC,
+            1 => Region::PAL,
+            2 => Region::Multi,
+            3 => Region::Dendy,
+            _ => unreachable!(),
+        };
+
+        let unsupported = RomError::Unsupported(Version::Nes2_0);
+        Ok(Header {
+            prg_size: rom_size(rom[4], rom[9] & 0x0F, PRG_BANK_SIZE as u32).ok_or(unsupported)?,
+            chr_size: rom_size(rom[5], rom[9] >> 4, 0x2000).ok_or(unsupported)?,
+            flags6: rom[6].into(),
+            flags7: rom[7].into(),
+            region,
             mapper,
+            submapper: rom[8] >> 4,
+            version: Version::Nes2_0,
+            prg_ram: ram_size(rom[10] & 0x0F),
+            prg_nvram: ram_size(rom[10] >> 4),
+            chr_ram: ram_size(rom[11] & 0x0F),
+            chr_nvram: ram_size(rom[11] >> 4),
+            console: Console::from_flags7(rom[7], rom[13]),
+            misc_roms: rom[14] & 0x03,
+            expansion: rom[15] & 0x3F,
         })
     }
 }
 
+// Sizes are either a count of banks, with the MSB nibble as the high bits, or with the nibble set
+// to $F, an exponent and multiplier of the form 2^E * (MM * 2 + 1).
+fn rom_size(lsb: u8, msb: u8, bank: u32) -> Option<u32> {
+    if msb == 0x0F {
+        let exponent = u32::from(lsb >> 2);
+        let multiplier = u32::from(lsb & 0x03) * 2 + 1;
+        1_u32.checked_shl(exponent)?.checked_mul(multiplier)
+    } else {
+        Some((u32::from(msb) << 8 | u32::from(lsb)) * bank)
+    }
+}
+
+// RAM sizes are given as a shift count of 64 bytes, with 0 meaning none at all.
+fn ram_size(shift: u8) -> u32 {
+    match shift {
+        0 => 0,
+        n => 64 << n,
+    }
+}
+
+impl Console {
+    fn from_flags7(flags7: u8, extra: u8) -> Self {
+        match flags7 & 0x03 {
+            0 => Console::Nes,
+            1 => Console::VsSystem {
+                ppu: extra & 0x0F,
+                hardware: extra >> 4,
+            },
+            2 => Console::PlayChoice,
+            3 => Console::Extended(extra & 0x0F),
+            _ => unreachable!(),
+        }
+    }
+}
+
 impl From<u8> for Flags6 {
     fn from(bits: u8) -> Self {
         let mirror = if bits & 8 != 0 {
-            Mirroring::Ignore
+            Mirroring::FourScreen
         } else if bits & 1 == 0 {
             Mirroring::Horizontal
         } else {
@@ -155,27 +290,33 @@ impl From<u8> for Flags7 {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct UnknownMapper(u16);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownMapper(pub u16);
 
-impl TryFrom<u8> for Mapper {
-    type Error = UnknownMapper;
-    fn try_from(id: u8) -> Result<Self, UnknownMapper> { Self::try_from(u16::from(id)) }
+impl Display for UnknownMapper {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Unknown Mapper ID: {:03x}", self.0)
+    }
 }
 
-impl TryFrom<u16> for Mapper {
-    type Error = UnknownMapper;
-    fn try_from(id: u16) -> Result<Self, UnknownMapper> {
-        match id {
-            0 => Ok(Self::NROM),
-            1 => Ok(Self::MMC1),
-            n => Err(UnknownMapper(n)),
-        }
-    }
+impl From<UnknownMapper> for RomError {
+    fn from(e: UnknownMapper) -> Self { RomError::Mapper(e) }
 }
 
-impl Display for UnknownMapper {
+impl Display for RomError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "Unknown Mapper ID: {:03x}", self.0)
+        match self {
+            RomError::Magic => write!(f, "Not an iNES ROM"),
+            RomError::TruncatedHeader => write!(f, "ROM header is truncated"),
+            RomError::TruncatedTrainer => write!(f, "ROM trainer is truncated"),
+            RomError::TruncatedPrg => write!(f, "PRG ROM is truncated"),
+            RomError::TruncatedChr => write!(f, "CHR ROM is truncated"),
+            RomError::PrgSize => write!(f, "PRG ROM isn't a whole number of 16KiB banks"),
+            RomError::ChrSize => write!(f, "CHR ROM isn't a whole number of 8KiB banks"),
+            RomError::Mapper(e) => e.fmt(f),
+            RomError::Unsupported(v) => write!(f, "Unsupported {:?} ROM", v),
+        }
     }
 }
+
+impl std::error::Error for RomError {}
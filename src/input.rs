@@ -0,0 +1,115 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use image::Rgb;
+
+mod four_score;
+mod mouse;
+mod power_pad;
+mod zapper;
+
+pub use four_score::FourScore;
+pub use mouse::{Mouse, MouseState};
+pub use power_pad::PowerPad;
+pub use zapper::{Aim, Zapper};
+
+// Anything that can be plugged into one of the two controller ports.
+pub trait InputDevice {
+    fn write(&mut self, strobe: bool);
+    // Only D0-D4 are driven by the port, everything above is left to open bus.
+    fn read(&mut self) -> u8;
+    fn set_buttons(&mut self, _buttons: Buttons) {}
+    // Called for every pixel as the PPU outputs it, for devices that look at the screen.
+    fn observe(&mut self, _point: (u32, u32), _color: Rgb<u8>) {}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Port {
+    One = 0,
+    Two = 1,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Buttons {
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Controller {
+    buttons: Rc<Cell<Buttons>>,
+    shift: u8,
+    strobe: bool,
+}
+
+impl Buttons {
+    pub const A: u8 = 0x01;
+    pub const B: u8 = 0x02;
+    pub const SELECT: u8 = 0x04;
+    pub const START: u8 = 0x08;
+    pub const UP: u8 = 0x10;
+    pub const DOWN: u8 = 0x20;
+    pub const LEFT: u8 = 0x40;
+    pub const RIGHT: u8 = 0x80;
+}
+
+impl From<u8> for Buttons {
+    fn from(bits: u8) -> Self {
+        Self {
+            a: bits & Self::A != 0,
+            b: bits & Self::B != 0,
+            select: bits & Self::SELECT != 0,
+            start: bits & Self::START != 0,
+            up: bits & Self::UP != 0,
+            down: bits & Self::DOWN != 0,
+            left: bits & Self::LEFT != 0,
+            right: bits & Self::RIGHT != 0,
+        }
+    }
+}
+
+impl From<Buttons> for u8 {
+    fn from(b: Buttons) -> u8 {
+        b.a as u8
+            | (b.b as u8) << 1
+            | (b.select as u8) << 2
+            | (b.start as u8) << 3
+            | (b.up as u8) << 4
+            | (b.down as u8) << 5
+            | (b.left as u8) << 6
+            | (b.right as u8) << 7
+    }
+}
+
+impl Controller {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn buttons(&self) -> Rc<Cell<Buttons>> { self.buttons.clone() }
+}
+
+impl InputDevice for Controller {
+    fn write(&mut self, strobe: bool) {
+        self.strobe = strobe;
+        if strobe {
+            self.shift = self.buttons.get().into();
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons.get().a as u8;
+        }
+        let bit = self.shift & 1;
+        // Official controllers report 1 once all eight buttons have been shifted out.
+        self.shift = (self.shift >> 1) | 0x80;
+        bit
+    }
+
+    fn set_buttons(&mut self, buttons: Buttons) { self.buttons.set(buttons); }
+}
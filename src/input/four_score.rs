@@ -0,0 +1,57 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use super::{Buttons, Controller, InputDevice, Port};
+
+// Each port of the Four Score reports two players followed by a signature byte.
+#[derive(Debug)]
+pub struct FourScore {
+    players: [Controller; 2],
+    signature: u8,
+    shift: u32,
+    strobe: bool,
+}
+
+impl FourScore {
+    pub fn new(port: Port) -> Self {
+        Self {
+            players: [Controller::new(), Controller::new()],
+            signature: match port {
+                Port::One => 0x08,
+                Port::Two => 0x04,
+            },
+            shift: 0,
+            strobe: false,
+        }
+    }
+
+    // Player 0 is the pad on the front of the adapter, player 1 the one sharing its port.
+    pub fn buttons(&self, player: usize) -> Rc<Cell<Buttons>> { self.players[player].buttons() }
+
+    fn latch(&self) -> u32 {
+        let [first, second] = &self.players;
+        u32::from(u8::from(first.buttons.get()))
+            | u32::from(u8::from(second.buttons.get())) << 8
+            | u32::from(self.signature) << 16
+    }
+}
+
+impl InputDevice for FourScore {
+    fn write(&mut self, strobe: bool) {
+        self.strobe = strobe;
+        if strobe {
+            self.shift = self.latch();
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.players[0].buttons.get().a as u8;
+        }
+        let bit = (self.shift & 1) as u8;
+        self.shift = (self.shift >> 1) | 1 << 23;
+        bit
+    }
+
+    fn set_buttons(&mut self, buttons: Buttons) { self.players[0].set_buttons(buttons); }
+}
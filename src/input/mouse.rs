@@ -0,0 +1,52 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use super::InputDevice;
+
+// Motion accumulates here until the next strobe, which reports and clears it.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct MouseState {
+    pub dx: i32,
+    pub dy: i32,
+    pub left: bool,
+    pub right: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Mouse {
+    state: Rc<Cell<MouseState>>,
+    shift: u32,
+    strobe: bool,
+}
+
+impl Mouse {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn state(&self) -> Rc<Cell<MouseState>> { self.state.clone() }
+
+    fn latch(&self) -> u32 {
+        let state = self.state.take();
+        self.state.set(MouseState { dx: 0, dy: 0, ..state });
+
+        // Displacements are sign and magnitude, with up and left being negative.
+        let delta = |d: i32| ((d < 0) as u8) << 7 | d.unsigned_abs().min(0x7f) as u8;
+        let buttons = (state.right as u8) << 7 | (state.left as u8) << 6 | 0x01;
+        u32::from_be_bytes([0, buttons, delta(state.dy), delta(state.dx)])
+    }
+}
+
+impl InputDevice for Mouse {
+    fn write(&mut self, strobe: bool) {
+        if self.strobe && !strobe {
+            self.shift = self.latch();
+        }
+        self.strobe = strobe;
+    }
+
+    // Unlike the NES pads, the report is shifted out most significant bit first.
+    fn read(&mut self) -> u8 {
+        let bit = (self.shift >> 31) as u8;
+        self.shift = (self.shift << 1) | 1;
+        bit
+    }
+}
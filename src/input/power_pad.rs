@@ -0,0 +1,57 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use super::InputDevice;
+
+// Buttons are numbered 1 through 12 as printed on side B of the mat.
+const D3_ORDER: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const D4_ORDER: [u8; 4] = [4, 3, 12, 8];
+
+#[derive(Debug, Default)]
+pub struct PowerPad {
+    pressed: Rc<Cell<u16>>,
+    d3: u8,
+    d4: u8,
+    strobe: bool,
+}
+
+impl PowerPad {
+    pub fn new() -> Self { Self::default() }
+
+    // Bit `n - 1` holds the state of button `n`.
+    pub fn pressed(&self) -> Rc<Cell<u16>> { self.pressed.clone() }
+
+    fn latch(&mut self) {
+        let pressed = self.pressed.get();
+        let collect = |order: &[u8]| {
+            order
+                .iter()
+                .enumerate()
+                .fold(0, |acc, (i, &n)| acc | ((pressed >> (n - 1)) as u8 & 1) << i)
+        };
+        self.d3 = collect(&D3_ORDER);
+        self.d4 = collect(&D4_ORDER) | 0xF0;
+    }
+}
+
+impl InputDevice for PowerPad {
+    fn write(&mut self, strobe: bool) {
+        self.strobe = strobe;
+        if strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        // The shift registers keep reloading while strobed, so nothing is shifted out.
+        if self.strobe {
+            self.latch();
+        }
+        let bits = (self.d3 & 1) << 3 | (self.d4 & 1) << 4;
+        if !self.strobe {
+            self.d3 = (self.d3 >> 1) | 0x80;
+            self.d4 = (self.d4 >> 1) | 0x80;
+        }
+        bits
+    }
+}
@@ -0,0 +1,50 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use image::Rgb;
+
+use super::InputDevice;
+
+// The photodiode keeps reporting light for roughly this many scanlines after the beam passes.
+const SENSE_LINES: u32 = 26;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Aim {
+    pub x: u32,
+    pub y: u32,
+    pub trigger: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Zapper {
+    aim: Rc<Cell<Aim>>,
+    sensed: Option<u32>,
+}
+
+impl Zapper {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn aim(&self) -> Rc<Cell<Aim>> { self.aim.clone() }
+}
+
+impl InputDevice for Zapper {
+    fn write(&mut self, _strobe: bool) {}
+
+    fn read(&mut self) -> u8 {
+        let light = self.sensed.is_none() as u8;
+        let trigger = self.aim.get().trigger as u8;
+        light << 3 | trigger << 4
+    }
+
+    fn observe(&mut self, (x, y): (u32, u32), Rgb([red, green, blue]): Rgb<u8>) {
+        let aim = self.aim.get();
+        if (x, y) == (aim.x, aim.y) {
+            let bright = u16::from(red) + u16::from(green) + u16::from(blue) >= 0x200;
+            self.sensed = if bright { Some(y) } else { None };
+        } else if let Some(line) = self.sensed {
+            if y < line || y >= line + SENSE_LINES {
+                self.sensed = None;
+            }
+        }
+    }
+}
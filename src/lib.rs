@@ -3,12 +3,19 @@
     step_trait,
     step_trait_ext,
     cell_update,
+    never_type,
 )]
 
-use std::sync::{Mutex, Arc};
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
+#[cfg(feature = "minifb")]
+use std::sync::atomic::AtomicU8;
 use std::sync::atomic::{AtomicBool, Ordering};
-use image::{ImageBuffer, Pixel};
-use genawaiter::stack::let_gen_using;
+use image::{Bgra, ImageBuffer, Pixel};
+use genawaiter::rc::Gen;
 use genawaiter::GeneratorState;
 
 macro_rules! get {
@@ -33,28 +40,57 @@ macro_rules! new_wrapping {
     }};
 }
 
-type Co<'a> = genawaiter::stack::Co<'a, MemoryOp, CycleData>;
+type Co = genawaiter::rc::Co<MemoryOp, CycleData>;
 
 mod audio;
 mod cpu;
 mod decode;
+mod dma;
 mod ines;
+pub mod input;
 mod memory;
 pub mod ppu;
+pub mod state;
+pub mod wav;
 
 use audio::Apu;
-use cpu::Cpu;
-pub use ines::Rom;
-use memory::{Cartridge, SysMemory};
+pub use audio::FilterPreset;
+use cpu::{Cpu, Interrupts, IrqSource};
+use dma::{DmcDma, OamDma};
+pub use ines::{Console, Mirroring, Region, Rom, RomError, UnknownMapper, Version};
+use input::{Controller, InputDevice};
+pub use input::{Buttons, Port};
+use memory::SysMemory;
+pub use memory::{Cartridge, Constructor, Mapper, Nametables, Registry};
 #[cfg(feature = "minifb")]
 use ppu::backend::Ppu;
 
-use ppu::render::{FrameBuffer, VOp};
+use ppu::render::{DrawCommand, FrameBuffer, Layer, RenderState, VOp};
 use ppu::{VReg, Vram};
+pub use state::StateError;
+use state::{Reader, Snapshot, Writer};
+
+pub type Frame = ImageBuffer<Bgra<u8>, Vec<u8>>;
+
+type CpuGen = Gen<MemoryOp, CycleData, Pin<Box<dyn Future<Output = Result<(), cpu::Error>>>>>;
+type PpuGen = Gen<(VOp, Option<DrawCommand>), u8, Pin<Box<dyn Future<Output = !>>>>;
+type FrameCallback<'a> = Box<dyn FnMut(&Frame) + 'a>;
 
 pub struct Nes<'a> {
-    pub cpu: Cpu,
+    cpu: Rc<Cell<Cpu>>,
     pub bus: MemBus<'a>,
+    cpu_cycle: CpuGen,
+    ppu_cycle: PpuGen,
+    render: Rc<RenderState>,
+    fb: Arc<Mutex<Frame>>,
+    on_frame: Option<FrameCallback<'a>>,
+    buf: CycleData,
+    vbuf: u8,
+    halted: bool,
+    // Whether the CPU is between instructions, where it can be restarted from its registers.
+    synced: bool,
+    battery: bool,
+    region: Region,
 }
 
 pub struct MemBus<'a> {
@@ -62,11 +98,19 @@ pub struct MemBus<'a> {
     memory: SysMemory,
     apu: Apu,
     pub ppu: Vram,
+    interrupts: Rc<Interrupts>,
+    dma: Option<OamDma>,
+    dmc: Option<DmcDma>,
+    ports: [Box<dyn InputDevice>; 2],
+    open_bus: u8,
 }
 
 enum MemoryOp {
     Read(u16),
     Write(u16, u8),
+    Idle,
+    // Not a bus cycle, the CPU has just finished an instruction.
+    Sync,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -77,29 +121,44 @@ struct CycleData {
 
 impl<'a> MemBus<'a> {
     fn get(&mut self, idx: u16) -> u8 {
-        match idx {
+        let val = match idx {
             0..=0x1fff => self.memory.get(idx),
             0x2000..=0x3FFF => {
                 let (byte, addr) = self.ppu.get_cpu(new_wrapping!(VReg, idx));
                 if let Some(addr) = addr {
-                    self.ppu.data_bus = self.ppu.get_ppu(addr, &self.cartridge);
+                    self.ppu.data_bus = self.ppu.get_ppu(addr, &mut self.cartridge);
                 }
                 byte
             }
             0x4015 => self.apu.get_status(),
+            // Only the low bits are driven by the controller ports, the rest is open bus.
+            0x4016 => (self.open_bus & 0xE0) | self.ports[Port::One as usize].read(),
+            0x4017 => (self.open_bus & 0xE0) | self.ports[Port::Two as usize].read(),
             0x4020..=0xffff => self.cartridge.get(idx),
             _ => 0,
-        }
+        };
+        self.open_bus = val;
+        val
     }
     fn set(&mut self, idx: u16, val: u8) {
+        self.open_bus = val;
         match idx {
             0..=0x1fff => self.memory.set(idx, val),
             0x2000..=0x3FFF => {
+                let nmi = self.ppu.registers.nmi_output();
                 if let Some(addr) = self.ppu.set_cpu(new_wrapping!(VReg, idx), val) {
                     self.ppu.set_ppu(addr, val, &mut self.cartridge);
                 }
+                // Enabling NMI while the vblank flag is still set fires it immediately.
+                if !nmi && self.ppu.registers.nmi_output() {
+                    self.interrupts.raise_nmi();
+                }
+                self.interrupts.set_nmi_enabled(self.ppu.registers.interrupt_enabled());
+                self.cartridge.set(idx, val);
             }
             0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write(idx, val),
+            0x4014 => self.dma = Some(OamDma::new(val)),
+            0x4016 => self.ports.iter_mut().for_each(|port| port.write(val & 1 != 0)),
             0x4020..=0xffff => self.cartridge.set(idx, val),
             _ => (),
         }
@@ -107,78 +166,392 @@ impl<'a> MemBus<'a> {
 }
 
 impl<'a> Nes<'a> {
-    pub fn new(rom: &'a Rom) -> Self {
+    // Only knows the mappers built into the emulator.
+    pub fn new(rom: &'a Rom) -> Result<Self, RomError> { Self::with_registry(rom, &Registry::default()) }
+
+    pub fn with_registry(rom: &'a Rom, registry: &Registry) -> Result<Self, RomError> {
         let mut cpu = Cpu::default();
 
+        let interrupts = Rc::new(Interrupts::new());
         let mut bus = MemBus {
-            cartridge: Cartridge::from_rom(rom),
+            cartridge: registry.build(rom)?,
             memory: SysMemory::new(),
-            apu: Apu::new(),
+            apu: Apu::new(interrupts.clone()),
             ppu: Vram::new(),
+            interrupts,
+            dma: None,
+            dmc: None,
+            ports: [Box::new(Controller::new()), Box::new(Controller::new())],
+            open_bus: 0,
         };
 
         cpu.set_pc(u16::from_le_bytes([bus.get(0xfffc), bus.get(0xfffd)]));
 
-        Self { cpu, bus }
+        let cpu = Rc::new(Cell::new(cpu));
+        let render = Rc::new(RenderState::default());
+
+        Ok(Self {
+            cpu_cycle: cpu_gen(&cpu, &bus, false),
+            ppu_cycle: ppu_gen(&bus, &render, 0),
+            cpu,
+            bus,
+            render,
+            fb: Arc::new(Mutex::new(ImageBuffer::new(256, 240))),
+            on_frame: None,
+            buf: CycleData { val: 0, cycles: 0 },
+            vbuf: 0,
+            halted: false,
+            synced: true,
+            battery: rom.has_battery(),
+            region: rom.region(),
+        })
     }
 
+    // Runs until the CPU halts by jumping to itself or the window is closed.
     pub fn run(&mut self) -> Result<(), cpu::Error> {
-        let Nes { cpu, ref mut bus } = self;
+        #[cfg(feature = "minifb")]
+        let input = Arc::new(AtomicU8::new(0));
+        #[cfg(feature = "minifb")]
+        let running = Ppu::open(self.fb.clone(), input.clone());
+        #[cfg(not(feature = "minifb"))]
+        let running = AtomicBool::new(true);
 
-        let fb = Arc::new(Mutex::new(ImageBuffer::new(256, 240)));
+        while running.load(Ordering::Relaxed) {
+            if !self.run_frame()? {
+                break;
+            }
+            #[cfg(feature = "minifb")]
+            self.set_buttons(Port::One, input.load(Ordering::Relaxed).into());
+        }
+        Ok(())
+    }
 
-        let_gen_using!(cpu_cycle, |co| cpu.run(co));
-        let_gen_using!(ppu_cycle, |co| FrameBuffer::clock(bus.ppu.registers.clone(), co));
+    // Each of the stepping functions returns false once the CPU has halted.
 
-        let mut buf = CycleData { val: 0, cycles: 0 };
-        let mut vbuf = 0;
+    pub fn step_cycle(&mut self) -> Result<bool, cpu::Error> {
+        loop {
+            match self.next_op()? {
+                None => return Ok(false),
+                Some(MemoryOp::Sync) => (),
+                Some(op) => {
+                    self.cycle(op);
+                    return Ok(true);
+                }
+            }
+        }
+    }
 
-        #[cfg(feature = "minifb")]
-        let running = Ppu::open(fb.clone());
-        #[cfg(not(feature = "minifb"))]
-        let running = AtomicBool::new(true);
+    pub fn step_instruction(&mut self) -> Result<bool, cpu::Error> {
+        loop {
+            match self.next_op()? {
+                None => return Ok(false),
+                Some(MemoryOp::Sync) => return Ok(true),
+                Some(op) => {
+                    self.cycle(op);
+                }
+            }
+        }
+    }
 
-        let mut temp_fb = None;
+    // Runs until the PPU reaches vblank, having output a full frame, then finishes the current
+    // instruction.
+    pub fn run_frame(&mut self) -> Result<bool, cpu::Error> {
+        let mut vblank = false;
+        loop {
+            match self.next_op()? {
+                None => return Ok(false),
+                Some(MemoryOp::Sync) if vblank => return Ok(true),
+                Some(MemoryOp::Sync) => (),
+                Some(op) => vblank |= self.cycle(op),
+            }
+        }
+    }
 
-        while running.load(Ordering::Relaxed) {
-            let op = match cpu_cycle.resume_with(buf) {
-                GeneratorState::Yielded(op) => op,
-                GeneratorState::Complete(Ok(())) => return Ok(()),
-                GeneratorState::Complete(Err(e)) => return Err(e),
-            };
+    fn next_op(&mut self) -> Result<Option<MemoryOp>, cpu::Error> {
+        if self.halted {
+            return Ok(None);
+        }
 
-            match op {
-                MemoryOp::Read(addr) => buf.val = bus.get(addr),
-                MemoryOp::Write(addr, val) => bus.set(addr, val),
-            };
+        let buf = self.buf;
+        self.synced = false;
 
-            if buf.cycles % 2 == 0 {
-                bus.apu.clock()
+        // DMC sample fetches stall the CPU for a few cycles, though they wait for OAM DMA to finish.
+        if self.bus.dmc.is_none() && self.bus.dma.is_none() {
+            self.bus.dmc = self.bus.apu.dmc_request().map(DmcDma::new);
+        }
+        if let Some(dma) = &mut self.bus.dmc {
+            match dma.next(buf.cycles) {
+                Some(op) => return Ok(Some(op)),
+                None => {
+                    self.bus.dmc = None;
+                    self.bus.apu.dmc_fill(buf.val);
+                }
             }
+        }
 
-            for _ in 0..3 {
-                let (cmd, draw) = match ppu_cycle.resume_with(vbuf) {
-                    GeneratorState::Yielded(cmd) => cmd,
-                    GeneratorState::Complete(never) => never,
-                };
-                match cmd {
-                    VOp::Fetch(addr) => vbuf = bus.ppu.get_ppu(addr, &bus.cartridge),
-                    VOp::Nop => (),
-                    VOp::Nmi => (), //todo!(),
-                };
-                if let Some(draw) = draw {
-                    let fb = temp_fb.get_or_insert_with(|| fb.lock().unwrap());
-                    fb[draw.point] = bus.ppu.palette.get_background(draw.tile, draw.palette).as_rgb().to_bgra()
-                } else {
-                    temp_fb.take();
+        // OAM DMA stalls the CPU, taking over the bus until the transfer is done.
+        if let Some(op) = self.bus.dma.as_mut().and_then(|dma| dma.next(buf.cycles, buf.val)) {
+            return Ok(Some(op));
+        }
+        self.bus.dma = None;
+
+        match self.cpu_cycle.resume_with(buf) {
+            GeneratorState::Yielded(op) => {
+                self.synced = matches!(op, MemoryOp::Sync);
+                Ok(Some(op))
+            }
+            GeneratorState::Complete(res) => {
+                self.halted = true;
+                res.map(|()| None)
+            }
+        }
+    }
+
+    // Performs a single CPU bus cycle along with the three PPU dots that happen alongside it,
+    // returning whether the PPU entered vblank.
+    fn cycle(&mut self, op: MemoryOp) -> bool {
+        let bus = &mut self.bus;
+        match op {
+            MemoryOp::Read(addr) => self.buf.val = bus.get(addr),
+            MemoryOp::Write(addr, val) => bus.set(addr, val),
+            MemoryOp::Idle | MemoryOp::Sync => (),
+        };
+
+        bus.apu.clock();
+        bus.cartridge.clock();
+
+        let frame = &self.fb;
+        let mut fb = None;
+        let mut vblank = false;
+        for _ in 0..3 {
+            let (cmd, draw) = match self.ppu_cycle.resume_with(self.vbuf) {
+                GeneratorState::Yielded(cmd) => cmd,
+                GeneratorState::Complete(never) => never,
+            };
+            match cmd {
+                VOp::Fetch(addr) => self.vbuf = bus.ppu.get_ppu(addr, &mut bus.cartridge),
+                VOp::Nop => (),
+                VOp::VBlank(nmi) => {
+                    if nmi {
+                        bus.interrupts.raise_nmi();
+                    }
+                    vblank = true;
                 }
+            };
+            if let Some(draw) = draw {
+                let fb = fb.get_or_insert_with(|| frame.lock().unwrap());
+                let color = match draw.layer {
+                    Layer::Background => bus.ppu.palette.get_background(draw.tile, draw.palette),
+                    Layer::Sprite => bus.ppu.palette.get_sprite(draw.tile, draw.palette),
+                };
+                let color = color.as_rgb();
+                bus.ports.iter_mut().for_each(|port| port.observe(draw.point, color));
+                fb[draw.point] = color.to_bgra()
             }
-            buf.cycles += 1;
         }
-        Ok(())
+        drop(fb);
+        bus.interrupts.set_irq(IrqSource::Mapper, bus.cartridge.irq());
+
+        if vblank {
+            if let Some(on_frame) = &mut self.on_frame {
+                on_frame(&frame.lock().unwrap());
+            }
+        }
+        self.buf.cycles += 1;
+        vblank
+    }
+
+    // The picture as drawn so far, complete whenever the PPU is in vblank.
+    pub fn frame(&self) -> MutexGuard<'_, Frame> { self.fb.lock().unwrap() }
+
+    // Called with the finished picture every time the PPU enters vblank.
+    pub fn on_frame(&mut self, callback: impl FnMut(&Frame) + 'a) { self.on_frame = Some(Box::new(callback)); }
+
+    pub fn cpu(&self) -> Cpu { self.cpu.get() }
+
+    // Taken from the ROM header, but not yet emulated: everything runs with NTSC timing, so PAL and
+    // Dendy games get 262 lines a frame, run at 60Hz and play audio at NTSC speed.
+    pub fn region(&self) -> Region { self.region }
+
+    // Mixed and filtered audio at the host sample rate, 44.1kHz unless changed. Samples range from
+    // -1.0 to 1.0, or 0.0 to 1.0 with the raw filter preset.
+    pub fn audio_samples(&mut self) -> impl Iterator<Item = f32> + '_ { self.bus.apu.samples() }
+
+    pub fn sample_rate(&self) -> u32 { self.bus.apu.sample_rate() }
+
+    pub fn set_sample_rate(&mut self, rate: u32) { self.bus.apu.set_sample_rate(rate); }
+
+    // Defaults to the front-loading NES.
+    pub fn set_audio_filter(&mut self, preset: FilterPreset) { self.bus.apu.set_filter(preset); }
+
+    // Runs headless for the given number of frames, collecting the audio as 16-bit PCM.
+    pub fn record_audio(&mut self, frames: usize) -> Result<Vec<i16>, cpu::Error> {
+        let mut pcm = Vec::new();
+        for _ in 0..frames {
+            let running = self.run_frame()?;
+            pcm.extend(self.audio_samples().map(wav::to_pcm));
+            if !running {
+                break;
+            }
+        }
+        Ok(pcm)
     }
 
-    pub fn set_pc(&mut self, pc: u16) { self.cpu.set_pc(pc); }
+    pub fn set_pc(&mut self, pc: u16) {
+        self.cpu.update(|mut cpu| {
+            cpu.set_pc(pc);
+            cpu
+        });
+    }
+
+    pub fn connect(&mut self, port: Port, device: Box<dyn InputDevice>) { self.bus.ports[port as usize] = device; }
+
+    pub fn set_buttons(&mut self, port: Port, buttons: Buttons) {
+        self.bus.ports[port as usize].set_buttons(buttons);
+    }
 
     pub fn get_mem(&mut self, addr: u16) -> u8 { self.bus.get(addr) }
+
+    // Pressing the reset button. Memory is left alone while the CPU runs its reset sequence, and
+    // the APU, PPU and mapper reset their registers.
+    pub fn reset(&mut self) {
+        self.bus.interrupts.clear();
+        self.bus.apu.reset();
+        self.bus.ppu.reset();
+        self.bus.cartridge.reset();
+        self.bus.dma = None;
+        self.bus.dmc = None;
+        self.halted = false;
+        self.synced = false;
+        self.cpu_cycle = cpu_gen(&self.cpu, &self.bus, true);
+    }
+
+    // Turning the console off and on again. RAM is cleared, except for battery backed work RAM.
+    pub fn power_on(&mut self) {
+        self.bus.interrupts.clear();
+        self.bus.memory = SysMemory::new();
+        self.bus.apu.power_on();
+        self.bus.ppu.power_on();
+        self.bus.cartridge.reset();
+        if let (false, Some(sram)) = (self.battery, self.bus.cartridge.sram_mut()) {
+            sram.fill(0);
+        }
+        self.bus.dma = None;
+        self.bus.dmc = None;
+        self.bus.open_bus = 0;
+
+        self.cpu.set(Cpu::power_on());
+        self.buf = CycleData { val: 0, cycles: 0 };
+        self.vbuf = 0;
+        self.halted = false;
+        self.synced = false;
+        self.render = Rc::new(RenderState::default());
+        self.cpu_cycle = cpu_gen(&self.cpu, &self.bus, true);
+        self.ppu_cycle = ppu_gen(&self.bus, &self.render, 0);
+    }
+
+    // The cartridge's work RAM, if it's battery backed and should be saved between runs.
+    pub fn sram(&self) -> Option<&[u8]> { self.bus.cartridge.sram().filter(|_| self.battery) }
+
+    // Restores previously saved work RAM, ignoring anything past its size.
+    pub fn load_sram(&mut self, data: &[u8]) {
+        if let Some(sram) = self.bus.cartridge.sram_mut() {
+            let len = data.len().min(sram.len());
+            sram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    // The whole machine, including a frame part way through being drawn. The CPU can only be saved
+    // between instructions, as it always is after `step_instruction`, `run_frame` or `run`.
+    pub fn save_state(&self) -> Result<Vec<u8>, StateError> {
+        if !self.synced && !self.halted {
+            return Err(StateError::Busy);
+        }
+        Ok(self.write_state())
+    }
+
+    // Input devices aren't part of the state. If anything fails to load, the machine is put back
+    // the way it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        // The CPU and PPU keep going until the whole state has loaded, so the backup holds all that
+        // needs restoring, even part way through an instruction.
+        let backup = self.write_state();
+        let render = match self.read_state(data) {
+            Ok(render) => render,
+            Err(e) => {
+                self.read_state(&backup).expect("restoring the state from before loading");
+                return Err(e);
+            }
+        };
+        self.synced = true;
+        self.bus.dma = None;
+        self.bus.dmc = None;
+
+        // The CPU restarts from its registers, and the PPU from the dot after the one saved.
+        self.render = Rc::new(render);
+        self.cpu_cycle = cpu_gen(&self.cpu, &self.bus, false);
+        self.ppu_cycle = ppu_gen(&self.bus, &self.render, self.vbuf);
+        Ok(())
+    }
+
+    fn write_state(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.bytes(state::MAGIC);
+        w.u16(state::VERSION);
+        self.bus.cartridge.save(&mut w);
+        self.cpu.get().save(&mut w);
+        self.bus.interrupts.save(&mut w);
+        self.bus.memory.save(&mut w);
+        self.bus.ppu.save(&mut w);
+        self.render.save(&mut w);
+        self.bus.apu.save(&mut w);
+
+        w.u64(self.buf.cycles);
+        w.u8(self.buf.val);
+        w.u8(self.vbuf);
+        w.u8(self.bus.open_bus);
+        w.bool(self.halted);
+        w.finish()
+    }
+
+    // Loads everything but where the PPU is in the frame, which is returned to restart it from.
+    fn read_state(&mut self, data: &[u8]) -> Result<RenderState, StateError> {
+        let mut r = Reader::new(data);
+        let mut magic = [0; 4];
+        r.bytes(&mut magic)?;
+        if &magic != state::MAGIC {
+            return Err(StateError::Magic);
+        }
+        match r.u16()? {
+            state::VERSION => (),
+            v => return Err(StateError::Version(v)),
+        }
+        self.bus.cartridge.load(&mut r)?;
+        let mut cpu = self.cpu.get();
+        cpu.load(&mut r)?;
+        self.cpu.set(cpu);
+        self.bus.interrupts.load(&mut r)?;
+        self.bus.memory.load(&mut r)?;
+        self.bus.ppu.load(&mut r)?;
+        let mut render = RenderState::default();
+        render.load(&mut r)?;
+        self.bus.apu.load(&mut r)?;
+
+        self.buf.cycles = r.u64()?;
+        self.buf.val = r.u8()?;
+        self.vbuf = r.u8()?;
+        self.bus.open_bus = r.u8()?;
+        self.halted = r.bool()?;
+        Ok(render)
+    }
+}
+
+fn cpu_gen(cpu: &Rc<Cell<Cpu>>, bus: &MemBus, reset: bool) -> CpuGen {
+    let (state, lines) = (cpu.clone(), bus.interrupts.clone());
+    Gen::new(|co| Box::pin(Cpu::run(state, lines, reset, co)) as _)
+}
+
+fn ppu_gen(bus: &MemBus, render: &Rc<RenderState>, byte: u8) -> PpuGen {
+    let (regs, oam, render) = (bus.ppu.registers.clone(), bus.ppu.oam.clone(), render.clone());
+    Gen::new(|co| Box::pin(FrameBuffer::clock(regs, oam, render, byte, co)) as _)
 }
@@ -1,25 +1,55 @@
 use std::env;
 use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind};
 use std::path::Path;
 
 use memmap::Mmap;
 use mynes::ppu::pattern::PTIdx;
-use mynes::{Nes, Rom};
+use mynes::{wav, Nes, Rom};
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let path = env::args_os().skip(1).next();
+    let mut args = env::args_os().skip(1);
+    let path = args.next();
     let path: &Path = path
         .as_ref()
         .map(|p| p.as_ref())
         .unwrap_or("./tests/roms/instr_test-v5/all_instrs.nes".as_ref());
     let rom = unsafe { Mmap::map(&File::open(path)?)? };
-    let rom = Rom::parse(&rom[..]).unwrap();
+    let rom = Rom::parse(&rom[..])?;
 
     // println!("{:#?}", rom.header);
-    let mut nes = Nes::new(&rom);
+    let mut nes = Nes::new(&rom)?;
     //nes.set_pc(0xC000);
-    nes.run().unwrap();
+
+    // Battery backed RAM lives in a .sav file next to the ROM.
+    let save = path.with_extension("sav");
+    if nes.sram().is_some() {
+        match fs::read(&save) {
+            Ok(data) => nes.load_sram(&data),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    // mynes <rom> --wav <out.wav> [frames] records audio without opening a window.
+    if args.next().map_or(false, |arg| arg == "--wav") {
+        let out = args.next().ok_or("missing output path")?;
+        let frames = match args.next() {
+            Some(frames) => frames.to_str().ok_or("invalid frame count")?.parse()?,
+            None => 600,
+        };
+        let pcm = nes.record_audio(frames).map_err(|e| e.to_string())?;
+        wav::write(BufWriter::new(File::create(out)?), nes.sample_rate(), &pcm)?;
+        return Ok(());
+    }
+
+    // The save is written even if the CPU hit an error, so progress isn't lost with it.
+    let result = nes.run();
+    if let Some(sram) = nes.sram() {
+        fs::write(&save, sram)?;
+    }
+    result.map_err(|e| e.to_string())?;
 
     //let mut ppu = Ppu::open()?;
     //ppu.show_background(&nes.bus.ppu.vram[0], nes.bus.cartridge.get_pattern_table(PTIdx::Left))?;
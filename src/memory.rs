@@ -1,12 +1,23 @@
-use crate::ines::{Mapper, Rom};
-use crate::ppu::pattern::{PTIdx, PatternTableRef};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+
+use crate::ines::{Mirroring, Rom, UnknownMapper};
 use crate::ppu::{Nametable, VAddr};
+use crate::state::{Reader, Snapshot, StateError, Writer};
 
+mod discrete;
 mod mmc1;
+mod mmc3;
+mod mmc5;
 mod nrom;
+mod sunsoft4;
 
+use discrete::{Board, Discrete};
 use mmc1::Mmc1;
+use mmc3::Mmc3;
+use mmc5::Mmc5;
 use nrom::NRom;
+use sunsoft4::Sunsoft4;
 
 pub const PRG_BANK_SIZE: usize = 0x4000;
 pub const CHR_BANK_SIZE: usize = 0x2000;
@@ -21,9 +32,64 @@ pub struct SysMemory {
     apu: [u8; 32],
 }
 
-pub enum Cartridge<'a> {
-    NRom(NRom<'a>),
-    Mmc1(Mmc1<'a>),
+// The board inside a cartridge, seeing everything the CPU does above $4020 along with its writes
+// to the PPU registers, and everything the PPU does below $3F00. Only the bus accesses are
+// required, the rest are for boards with extra hardware.
+pub trait Mapper: Snapshot {
+    fn get(&self, addr: u16) -> u8;
+    fn set(&mut self, addr: u16, val: u8);
+
+    // Only called for the pattern tables, $0000-$1FFF.
+    fn get_ppu(&self, addr: VAddr) -> u8;
+    fn set_ppu(&mut self, addr: VAddr, val: u8);
+
+    // Which nametables appear at each of $2000, $2400, $2800 and $2C00, either the console's two or
+    // any the board has of its own.
+    fn mirror<'nt>(&'nt self, vram: &'nt [Nametable; 2]) -> [&'nt Nametable; 4];
+
+    // $2000-$3EFF, for boards that can put something other than nametable RAM there, like CHR ROM.
+    fn get_nametable(&self, addr: VAddr, vram: &[Nametable; 2]) -> u8 {
+        self.mirror(vram)[usize::from(addr.get() >> 10) % 4].read(addr.get() % 0x400)
+    }
+
+    fn set_nametable(&mut self, addr: VAddr, val: u8, vram: &[Nametable; 2]) {
+        self.mirror(vram)[usize::from(addr.get() >> 10) % 4].write(addr.get() % 0x400, val);
+    }
+
+    // The level of the cartridge's /IRQ line, checked every CPU cycle.
+    fn irq(&self) -> bool { false }
+
+    // Sees every address the PPU reads or writes, for boards watching lines like A12.
+    fn snoop(&mut self, _addr: VAddr) {}
+
+    // Called once per CPU cycle.
+    fn clock(&mut self) {}
+
+    fn reset(&mut self) {}
+
+    // Work RAM that can be saved between runs when the cartridge has a battery.
+    fn sram(&self) -> Option<&[u8]> { None }
+    fn sram_mut(&mut self) -> Option<&mut [u8]> { None }
+}
+
+pub type Constructor = for<'a> fn(&Rom<'a>) -> Box<dyn Mapper + 'a>;
+
+// Mapper constructors by iNES mapper number, and optionally NES 2.0 submapper. A constructor
+// without a submapper is used for any submapper that doesn't have its own.
+#[derive(Clone)]
+pub struct Registry(HashMap<(u16, Option<u8>), Constructor>);
+
+pub struct Cartridge<'a> {
+    mapper: Box<dyn Mapper + 'a>,
+    id: (u16, u8),
+}
+
+// The nametables as arranged by the header, with four screen boards carrying another 2KiB of their
+// own for $2800 and $2C00.
+#[derive(Debug, Clone)]
+pub struct Nametables {
+    mirror: Mirroring,
+    own: Option<Box<[Nametable; 2]>>,
 }
 
 pub struct CPU;
@@ -59,51 +125,169 @@ impl SysMemory {
     }
 }
 
-impl<'a> Cartridge<'a> {
-    pub fn get(&self, idx: u16) -> u8 {
-        match self {
-            Cartridge::NRom(c) => c.get(idx),
-            Cartridge::Mmc1(c) => c.get(idx),
-        }
+impl Snapshot for SysMemory {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(&self.ram);
+        w.bytes(&self.ppu);
+        w.bytes(&self.apu);
     }
-    pub fn set(&mut self, idx: u16, val: u8) {
-        match self {
-            Cartridge::NRom(c) => c.set(idx, val),
-            Cartridge::Mmc1(c) => c.set(idx, val),
-        }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        r.bytes(&mut self.ram)?;
+        r.bytes(&mut self.ppu)?;
+        r.bytes(&mut self.apu)
+    }
+}
+
+impl Nametables {
+    pub fn new(mirror: Mirroring) -> Self {
+        let own = (mirror == Mirroring::FourScreen)
+            .then(|| Box::new([Nametable::new(), Nametable::new()]));
+        Self { mirror, own }
     }
 
-    pub fn get_ppu(&self, idx: VAddr) -> u8 {
-        match self {
-            Cartridge::NRom(c) => c.get_ppu(idx),
-            Cartridge::Mmc1(c) => c.get_ppu(idx),
+    // For mappers that switch between horizontal and vertical, which four screen boards ignore.
+    pub fn set_mirror(&mut self, mirror: Mirroring) {
+        if self.own.is_none() {
+            self.mirror = mirror;
         }
     }
-    pub fn set_ppu(&mut self, idx: VAddr, val: u8) {
-        match self {
-            Cartridge::NRom(_c) => todo!("NROM CHR ram"),
-            Cartridge::Mmc1(c) => c.set_ppu(idx, val),
+
+    pub fn arrange<'nt>(&'nt self, vram: &'nt [Nametable; 2]) -> [&'nt Nametable; 4] {
+        match (&self.own, self.mirror) {
+            (Some(own), _) => [&vram[0], &vram[1], &own[0], &own[1]],
+            (None, Mirroring::Horizontal) => [&vram[0], &vram[0], &vram[1], &vram[1]],
+            (None, _) => [&vram[0], &vram[1], &vram[0], &vram[1]],
         }
     }
+}
 
-    pub fn mirror<'nt>(&self, vram: &'nt [Nametable; 2]) -> [&'nt Nametable; 4] {
-        match self {
-            Cartridge::NRom(c) => c.mirror(vram),
-            Cartridge::Mmc1(c) => c.mirror(vram),
+impl Snapshot for Nametables {
+    fn save(&self, w: &mut Writer) {
+        w.bool(self.mirror == Mirroring::Horizontal);
+        if let Some(own) = &self.own {
+            own.iter().for_each(|nt| nt.save(w));
         }
     }
 
-    pub fn from_rom(rom: &Rom<'a>) -> Self {
-        match rom.mapper() {
-            Mapper::NROM => Self::NRom(NRom::new(rom.prg, rom.chr, rom.mirror())),
-            Mapper::MMC1 => Self::Mmc1(Mmc1::new(rom.prg, rom.chr, rom.mirror())),
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        let mirror = if r.bool()? { Mirroring::Horizontal } else { Mirroring::Vertical };
+        self.set_mirror(mirror);
+        if let Some(own) = &mut self.own {
+            for nt in own.iter_mut() {
+                nt.load(r)?;
+            }
         }
+        Ok(())
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Registry(HashMap::new());
+        registry.register(0, None, nrom);
+        registry.register(1, None, mmc1);
+        registry.register(2, None, uxrom);
+        registry.register(3, None, cnrom);
+        registry.register(4, None, mmc3);
+        registry.register(4, Some(4), mmc3a);
+        registry.register(5, None, mmc5);
+        registry.register(7, None, axrom);
+        registry.register(66, None, gxrom);
+        registry.register(68, None, sunsoft4);
+        registry
+    }
+}
+
+impl Registry {
+    // Has every mapper built into the emulator.
+    pub fn new() -> Self { Self::default() }
+
+    pub fn register(&mut self, mapper: u16, submapper: Option<u8>, new: Constructor) {
+        self.0.insert((mapper, submapper), new);
+    }
+
+    pub fn build<'a>(&self, rom: &Rom<'a>) -> Result<Cartridge<'a>, UnknownMapper> {
+        let id = (rom.mapper(), rom.submapper());
+        let new = self
+            .0
+            .get(&(id.0, Some(id.1)))
+            .or_else(|| self.0.get(&(id.0, None)))
+            .ok_or(UnknownMapper(id.0))?;
+        Ok(Cartridge { mapper: new(rom), id })
+    }
+}
+
+fn nrom<'a>(rom: &Rom<'a>) -> Box<dyn Mapper + 'a> {
+    let chr_ram = rom.chr_ram_size() + rom.chr_nvram_size();
+    Box::new(NRom::new(rom.prg, rom.chr, chr_ram as usize, rom.mirror()))
+}
+
+fn mmc1<'a>(rom: &Rom<'a>) -> Box<dyn Mapper + 'a> {
+    let prg_ram = rom.prg_ram_size() + rom.prg_nvram_size();
+    let chr_ram = rom.chr_ram_size() + rom.chr_nvram_size();
+    Box::new(Mmc1::new(rom.prg, rom.chr, prg_ram as usize, chr_ram as usize, rom.mirror()))
+}
+
+// Submapper 2 marks the boards with bus conflicts, which GxROM always has.
+fn discrete<'a>(board: Board, rom: &Rom<'a>) -> Box<dyn Mapper + 'a> {
+    let chr_ram = rom.chr_ram_size() + rom.chr_nvram_size();
+    let bus_conflicts = board == Board::Gxrom || rom.submapper() == 2;
+    Box::new(Discrete::new(board, rom.prg, rom.chr, chr_ram as usize, rom.mirror(), bus_conflicts))
+}
+
+fn uxrom<'a>(rom: &Rom<'a>) -> Box<dyn Mapper + 'a> { discrete(Board::Uxrom, rom) }
+
+fn cnrom<'a>(rom: &Rom<'a>) -> Box<dyn Mapper + 'a> { discrete(Board::Cnrom, rom) }
+
+fn axrom<'a>(rom: &Rom<'a>) -> Box<dyn Mapper + 'a> { discrete(Board::Axrom, rom) }
+
+fn gxrom<'a>(rom: &Rom<'a>) -> Box<dyn Mapper + 'a> { discrete(Board::Gxrom, rom) }
+
+fn mmc3<'a>(rom: &Rom<'a>) -> Box<dyn Mapper + 'a> {
+    let chr_ram = rom.chr_ram_size() + rom.chr_nvram_size();
+    Box::new(Mmc3::new(rom.prg, rom.chr, chr_ram as usize, rom.mirror(), false))
+}
+
+// Submapper 4 is the older MMC3A, with a different IRQ.
+fn mmc3a<'a>(rom: &Rom<'a>) -> Box<dyn Mapper + 'a> {
+    let chr_ram = rom.chr_ram_size() + rom.chr_nvram_size();
+    Box::new(Mmc3::new(rom.prg, rom.chr, chr_ram as usize, rom.mirror(), true))
+}
+
+fn mmc5<'a>(rom: &Rom<'a>) -> Box<dyn Mapper + 'a> {
+    let chr_ram = rom.chr_ram_size() + rom.chr_nvram_size();
+    let prg_ram = rom.prg_ram_size() + rom.prg_nvram_size();
+    Box::new(Mmc5::new(rom.prg, rom.chr, chr_ram as usize, prg_ram as usize))
+}
+
+fn sunsoft4<'a>(rom: &Rom<'a>) -> Box<dyn Mapper + 'a> {
+    let chr_ram = rom.chr_ram_size() + rom.chr_nvram_size();
+    Box::new(Sunsoft4::new(rom.prg, rom.chr, chr_ram as usize))
+}
+
+impl<'a> Deref for Cartridge<'a> {
+    type Target = dyn Mapper + 'a;
+
+    fn deref(&self) -> &Self::Target { &*self.mapper }
+}
+
+impl<'a> DerefMut for Cartridge<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target { &mut *self.mapper }
+}
+
+// The mapper is recorded first so a state can't be loaded into a different board.
+impl<'a> Snapshot for Cartridge<'a> {
+    fn save(&self, w: &mut Writer) {
+        w.u16(self.id.0);
+        w.u8(self.id.1);
+        self.mapper.save(w);
     }
 
-    pub fn get_pattern_table(&'a self, idx: PTIdx) -> PatternTableRef<'a> {
-        match self {
-            Cartridge::NRom(c) => c.get_pattern_table(idx),
-            Cartridge::Mmc1(c) => c.get_pattern_table(idx),
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        if (r.u16()?, r.u8()?) != self.id {
+            return Err(StateError::Mapper);
         }
+        self.mapper.load(r)
     }
 }
@@ -0,0 +1,128 @@
+use std::borrow::Cow;
+
+use super::{Mapper, Nametables};
+use crate::ines::Mirroring;
+use crate::ppu::{Nametable, VAddr};
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
+// Boards built from off the shelf logic, with a single latch written anywhere in $8000-$FFFF.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Board {
+    // Mapper 2: switchable 16KiB at $8000, with the last bank fixed at $C000.
+    Uxrom,
+    // Mapper 3: switchable 8KiB CHR.
+    Cnrom,
+    // Mapper 7: switchable 32KiB PRG and single screen mirroring.
+    Axrom,
+    // Mapper 66: switchable 32KiB PRG and 8KiB CHR.
+    Gxrom,
+}
+
+pub struct Discrete<'a> {
+    board: Board,
+    prg_rom: &'a [u8],
+    chr: Cow<'a, [u8]>,
+    nametables: Nametables,
+    bus_conflicts: bool,
+    latch: u8,
+}
+
+impl<'a> Discrete<'a> {
+    // Boards without CHR ROM have RAM in its place instead.
+    pub fn new(
+        board: Board,
+        prg_rom: &'a [u8],
+        chr_rom: &'a [u8],
+        chr_ram: usize,
+        mirror: Mirroring,
+        bus_conflicts: bool,
+    ) -> Self {
+        let chr = if chr_rom.is_empty() {
+            Cow::Owned(vec![0; chr_ram.max(0x2000)])
+        } else {
+            Cow::Borrowed(chr_rom)
+        };
+        Self {
+            board,
+            prg_rom,
+            chr,
+            nametables: Nametables::new(mirror),
+            bus_conflicts,
+            latch: 0,
+        }
+    }
+
+    fn prg_addr(&self, idx: u16) -> usize {
+        let idx = usize::from(idx) - 0x8000;
+        let latch = usize::from(self.latch);
+        let addr = match self.board {
+            Board::Uxrom if idx < 0x4000 => latch * 0x4000 + idx,
+            Board::Uxrom => self.prg_rom.len().saturating_sub(0x4000) + idx % 0x4000,
+            Board::Cnrom => idx,
+            Board::Axrom => (latch & 0x07) * 0x8000 + idx,
+            Board::Gxrom => (latch >> 4 & 0x03) * 0x8000 + idx,
+        };
+        addr % self.prg_rom.len()
+    }
+
+    fn chr_addr(&self, idx: VAddr) -> usize {
+        let idx = usize::from(idx.get());
+        let latch = usize::from(self.latch);
+        let addr = match self.board {
+            Board::Cnrom => latch * 0x2000 + idx,
+            Board::Gxrom => (latch & 0x03) * 0x2000 + idx,
+            Board::Uxrom | Board::Axrom => idx,
+        };
+        addr % self.chr.len()
+    }
+}
+
+impl<'a> Mapper for Discrete<'a> {
+    fn get(&self, idx: u16) -> u8 {
+        match idx {
+            0x8000..=0xffff => self.prg_rom[self.prg_addr(idx)],
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, idx: u16, val: u8) {
+        if let 0x8000..=0xffff = idx {
+            // The ROM drives the bus too, so where the two disagree the latch sees a 0.
+            self.latch = if self.bus_conflicts { val & self.get(idx) } else { val };
+        }
+    }
+
+    fn get_ppu(&self, idx: VAddr) -> u8 { self.chr[self.chr_addr(idx)] }
+
+    fn set_ppu(&mut self, idx: VAddr, val: u8) {
+        let addr = self.chr_addr(idx);
+        if let Cow::Owned(chr) = &mut self.chr {
+            chr[addr] = val;
+        }
+    }
+
+    fn mirror<'nt>(&'nt self, vram: &'nt [Nametable; 2]) -> [&'nt Nametable; 4] {
+        match self.board {
+            Board::Axrom => [&vram[usize::from(self.latch >> 4 & 1)]; 4],
+            _ => self.nametables.arrange(vram),
+        }
+    }
+}
+
+impl<'a> Snapshot for Discrete<'a> {
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.latch);
+        if let Cow::Owned(chr) = &self.chr {
+            w.bytes(chr);
+        }
+        self.nametables.save(w);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.latch = r.u8()?;
+        if let Cow::Owned(chr) = &mut self.chr {
+            r.bytes(chr)?;
+        }
+        self.nametables.load(r)
+    }
+}
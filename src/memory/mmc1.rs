@@ -1,17 +1,18 @@
 use std::borrow::Cow;
 
-use super::{CHRBank, PRGBank};
+use super::{Mapper, PRGBank};
 use crate::ines;
-use crate::memory::CHR_BANK_SIZE;
-use crate::ppu::pattern::{PTIdx, PatternTableRef};
 use crate::ppu::{Nametable, VAddr};
+use crate::state::{Reader, Snapshot, StateError, Writer};
 
 pub struct Mmc1<'a> {
     prg_rom: &'a [PRGBank],
+    chr_rom: &'a [[u8; 0x1000]],
     chr: Cow<'a, [[u8; 0x1000]]>,
-    sram: Option<[u8; 0x2000]>,
+    sram: Vec<u8>,
+    sram_enabled: bool,
 
-    prg_banks: [&'a PRGBank; 2],
+    prg_banks: [usize; 2],
     chr_banks: [usize; 2],
     settings: Settings,
 
@@ -35,6 +36,17 @@ impl Default for Settings {
     }
 }
 
+impl From<&Settings> for u8 {
+    fn from(s: &Settings) -> u8 {
+        let prg_mode = match s.prg_mode {
+            PRGMode::Full => 0,
+            PRGMode::FixFirst => 2,
+            PRGMode::FixLast => 3,
+        };
+        s.mirror as u8 | prg_mode << 2 | ((s.chr_mode == CHRMode::Half) as u8) << 4
+    }
+}
+
 impl From<u8> for Settings {
     fn from(shift: u8) -> Self {
         let mirror = match shift % 4 {
@@ -64,36 +76,42 @@ impl From<u8> for Settings {
 }
 
 impl<'a> Mmc1<'a> {
-    pub fn new(prg_rom: &'a [u8], chr_rom: &'a [u8], mirror: ines::Mirroring) -> Self {
+    // Boards without CHR ROM have RAM in its place instead. PRG RAM past the first 8KiB isn't
+    // banked in yet.
+    pub fn new(
+        prg_rom: &'a [u8],
+        chr_rom: &'a [u8],
+        prg_ram: usize,
+        chr_ram: usize,
+        mirror: ines::Mirroring,
+    ) -> Self {
         let (prg_rom, extra) = prg_rom.as_chunks();
         debug_assert_eq!(extra, &[] as &[u8]);
         let (chr_rom, extra) = chr_rom.as_chunks();
         debug_assert_eq!(extra, &[] as &[u8]);
+        let chr = if chr_rom.is_empty() {
+            Cow::Owned(vec![[0; 0x1000]; chr_ram.max(0x2000) / 0x1000])
+        } else {
+            Cow::Borrowed(chr_rom)
+        };
         Self {
             prg_rom,
-            chr: Cow::Borrowed(chr_rom),
-            sram: Some([0; 0x2000]),
+            chr_rom,
+            chr,
+            sram: vec![0; prg_ram],
+            sram_enabled: true,
 
             settings: Settings {
                 mirror: mirror.into(),
                 ..Settings::default()
             },
 
-            prg_banks: [prg_rom.first().unwrap(), prg_rom.last().unwrap()],
+            prg_banks: [0, prg_rom.len() - 1],
             chr_banks: [0, 1],
             shift: 0,
             count: 0,
         }
     }
-
-    pub fn mirror<'nt>(&self, vram: &'nt [Nametable; 2]) -> [&'nt Nametable; 4] {
-        match self.settings.mirror {
-            Mirroring::Horizontal => [&vram[0], &vram[0], &vram[1], &vram[1]],
-            Mirroring::Vertical => [&vram[0], &vram[1], &vram[0], &vram[1]],
-            Mirroring::Lower => [&vram[0], &vram[0], &vram[0], &vram[0]],
-            Mirroring::Upper => [&vram[1], &vram[1], &vram[1], &vram[1]],
-        }
-    }
 }
 
 const CONTROL: u16 = 0;
@@ -101,6 +119,7 @@ const CHR_1: u16 = 1;
 const CHR_2: u16 = 2;
 const PRG: u16 = 3;
 
+#[derive(Copy, Clone)]
 enum Mirroring {
     Lower = 0,
     Upper = 1,
@@ -113,7 +132,7 @@ impl From<ines::Mirroring> for Mirroring {
         match m {
             ines::Mirroring::Horizontal => Mirroring::Horizontal,
             ines::Mirroring::Vertical => Mirroring::Vertical,
-            ines::Mirroring::Ignore => Mirroring::Lower,
+            ines::Mirroring::FourScreen => Mirroring::Lower,
         }
     }
 }
@@ -130,29 +149,26 @@ enum CHRMode {
     Half,
 }
 
-const EMPTY_PATTERN: &'static [u8; 0x1000] = &[0; 0x1000];
-
-impl<'a> Mmc1<'a> {
-    pub fn get(&self, idx: u16) -> u8 {
+impl<'a> Mapper for Mmc1<'a> {
+    fn get(&self, idx: u16) -> u8 {
         let idx = usize::from(idx);
         match idx {
-            0x6000..=0x7fff => self
-                .sram
-                .as_ref()
-                .map(|sram| sram[idx % 0x2000])
-                .unwrap_or(0),
-            0x8000..=0xbfff => self.prg_banks[0][idx - 0x8000],
-            0xc000..=0xffff => self.prg_banks[1][idx - 0xc000],
+            0x6000..=0x7fff if self.sram_enabled && !self.sram.is_empty() => {
+                self.sram[(idx - 0x6000) % self.sram.len()]
+            }
+            0x8000..=0xbfff => self.prg_rom[self.prg_banks[0]][idx - 0x8000],
+            0xc000..=0xffff => self.prg_rom[self.prg_banks[1]][idx - 0xc000],
             _ => 0,
         }
     }
 
-    pub fn set(&mut self, idx: u16, val: u8) {
+    fn set(&mut self, idx: u16, val: u8) {
         match idx {
             0x6000..=0x7fff => {
-                self.sram
-                    .as_mut()
-                    .map(|sram| sram[usize::from(idx) - 0x6000] = val);
+                if self.sram_enabled && !self.sram.is_empty() {
+                    let len = self.sram.len();
+                    self.sram[(usize::from(idx) - 0x6000) % len] = val;
+                }
             }
             0x8000..=0xffff => {
                 if val & (1 << 7) != 0 {
@@ -171,8 +187,8 @@ impl<'a> Mmc1<'a> {
                             CONTROL => {
                                 self.settings = Settings::from(shift);
                                 /*match self.settings.prg_mode {
-                                    PRGMode::FixFirst => self.prg_banks[0] = self.prg_rom.first().unwrap(),
-                                    PRGMode::FixLast => self.prg_banks[1] = self.prg_rom.last().unwrap(),
+                                    PRGMode::FixFirst => self.prg_banks[0] = 0,
+                                    PRGMode::FixLast => self.prg_banks[1] = self.prg_rom.len() - 1,
                                     PRGMode::Full => (),
                                 }*/
                             }
@@ -186,26 +202,18 @@ impl<'a> Mmc1<'a> {
                                 }
                             }
                             PRG => {
-                                match (shift & (1 << 4) != 0, &self.sram) {
-                                    (true, &None) => self.sram = Some([0; 0x2000]),
-                                    (false, &Some(_)) => self.sram = None,
-                                    _ => (),
-                                }
+                                // Disabling the RAM keeps its contents, which may be battery backed.
+                                self.sram_enabled = shift & (1 << 4) == 0;
                                 let val = val % 0x0F;
                                 match self.settings.prg_mode {
-                                    PRGMode::Full => {
-                                        self.prg_banks =
-                                            [&self.prg_rom[val & !1], &self.prg_rom[val | 1]];
-                                    }
-                                    PRGMode::FixFirst => {
-                                        self.prg_banks =
-                                            [self.prg_rom.first().unwrap(), &self.prg_rom[val]];
-                                    }
+                                    PRGMode::Full => self.prg_banks = [val & !1, val | 1],
+                                    PRGMode::FixFirst => self.prg_banks = [0, val],
                                     PRGMode::FixLast => {
-                                        self.prg_banks =
-                                            [&self.prg_rom[val], self.prg_rom.last().unwrap()];
+                                        self.prg_banks = [val, self.prg_rom.len() - 1];
                                     }
                                 }
+                                let len = self.prg_rom.len();
+                                self.prg_banks.iter_mut().for_each(|bank| *bank %= len);
                             }
                             _ => unreachable!(),
                         };
@@ -219,15 +227,28 @@ impl<'a> Mmc1<'a> {
         }
     }
 
-    pub fn get_pattern_table(&'a self, idx: PTIdx) -> PatternTableRef<'a> {
-        let bank = match idx {
-            PTIdx::Left => self.chr_banks[0],
-            PTIdx::Right => self.chr_banks[1],
-        };
-        PatternTableRef(&self.chr.get(bank).unwrap_or(EMPTY_PATTERN))
+    // Behaves like a write with bit 7 set, which also fixes the last PRG bank.
+    fn reset(&mut self) {
+        self.shift = 0;
+        self.count = 0;
+        self.settings.prg_mode = PRGMode::FixLast;
+        self.prg_banks[1] = self.prg_rom.len() - 1;
     }
 
-    pub fn get_ppu(&self, idx: VAddr) -> u8 {
+    fn sram(&self) -> Option<&[u8]> { Some(&self.sram) }
+
+    fn sram_mut(&mut self) -> Option<&mut [u8]> { Some(&mut self.sram) }
+
+    fn mirror<'nt>(&self, vram: &'nt [Nametable; 2]) -> [&'nt Nametable; 4] {
+        match self.settings.mirror {
+            Mirroring::Horizontal => [&vram[0], &vram[0], &vram[1], &vram[1]],
+            Mirroring::Vertical => [&vram[0], &vram[1], &vram[0], &vram[1]],
+            Mirroring::Lower => [&vram[0], &vram[0], &vram[0], &vram[0]],
+            Mirroring::Upper => [&vram[1], &vram[1], &vram[1], &vram[1]],
+        }
+    }
+
+    fn get_ppu(&self, idx: VAddr) -> u8 {
         if let 0x0000..=0x1FFF = idx.get() {
             let bank = usize::from(idx.get() / 0x1000);
             self.chr
@@ -238,7 +259,7 @@ impl<'a> Mmc1<'a> {
             0
         }
     }
-    pub fn set_ppu(&mut self, idx: VAddr, val: u8) {
+    fn set_ppu(&mut self, idx: VAddr, val: u8) {
         use std::iter::repeat;
         if let 0x0000..=0x1FFF = idx.get() {
             let bank = self.chr_banks[usize::from(idx.get() / 0x1000)];
@@ -248,3 +269,63 @@ impl<'a> Mmc1<'a> {
         }
     }
 }
+
+impl<'a> Snapshot for Mmc1<'a> {
+    fn save(&self, w: &mut Writer) {
+        // CHR is only saved once it's been written to.
+        match &self.chr {
+            Cow::Borrowed(_) => w.u32(0),
+            Cow::Owned(chr) => {
+                w.u32(chr.len() as u32);
+                chr.iter().for_each(|bank| w.bytes(bank));
+            }
+        }
+        w.bool(self.sram_enabled);
+        w.bytes(&self.sram);
+
+        self.prg_banks.iter().for_each(|&b| w.u8(b as u8));
+        self.chr_banks.iter().for_each(|&b| w.u8(b as u8));
+        w.u8((&self.settings).into());
+
+        w.u8(self.shift);
+        w.u8(self.count);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.chr = match r.u32()? {
+            0 => Cow::Borrowed(self.chr_rom),
+            // Writes only grow CHR RAM as far as the largest 4KiB bank number, which is 5 bits.
+            len if len as usize > self.chr_rom.len().max(0x20) => {
+                return Err(StateError::Invalid("CHR RAM size"));
+            }
+            len => {
+                let mut chr = vec![[0; 0x1000]; len as usize];
+                for bank in chr.iter_mut() {
+                    r.bytes(bank)?;
+                }
+                Cow::Owned(chr)
+            }
+        };
+        self.sram_enabled = r.bool()?;
+        r.bytes(&mut self.sram)?;
+
+        // Older states kept bank numbers as written, before wrapping them around PRG ROM.
+        for bank in self.prg_banks.iter_mut() {
+            *bank = usize::from(r.u8()?) % self.prg_rom.len();
+        }
+        for bank in self.chr_banks.iter_mut() {
+            *bank = usize::from(r.u8()?);
+            if *bank >= 0x20 {
+                return Err(StateError::Invalid("CHR bank"));
+            }
+        }
+        self.settings = r.u8()?.into();
+
+        self.shift = r.u8()?;
+        self.count = r.u8()?;
+        if self.count >= 5 {
+            return Err(StateError::Invalid("MMC1 shift count"));
+        }
+        Ok(())
+    }
+}
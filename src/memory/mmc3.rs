@@ -0,0 +1,237 @@
+use std::borrow::Cow;
+
+use super::{Mapper, Nametables};
+use crate::ines::Mirroring;
+use crate::ppu::{Nametable, VAddr};
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
+pub struct Mmc3<'a> {
+    prg_rom: &'a [u8],
+    chr: Cow<'a, [u8]>,
+    sram: [u8; 0x2000],
+    sram_enabled: bool,
+    sram_writable: bool,
+    nametables: Nametables,
+
+    // Bit 7 swaps the CHR halves, bit 6 the PRG banks at $8000 and $C000, and the low bits pick
+    // which of the bank registers $8001 writes.
+    select: u8,
+    banks: [u8; 8],
+
+    irq: Counter,
+    // The level of A12, and for how many CPU cycles it's been low.
+    a12: bool,
+    a12_low: u8,
+}
+
+#[derive(Debug, Default)]
+struct Counter {
+    // MMC3A only raises IRQ when counting down to 0, not when staying there.
+    alternate: bool,
+    latch: u8,
+    counter: u8,
+    reload: bool,
+    enabled: bool,
+    pending: bool,
+}
+
+impl<'a> Mmc3<'a> {
+    // Boards without CHR ROM have RAM in its place instead.
+    pub fn new(
+        prg_rom: &'a [u8],
+        chr_rom: &'a [u8],
+        chr_ram: usize,
+        mirror: Mirroring,
+        alternate: bool,
+    ) -> Self {
+        let chr = if chr_rom.is_empty() {
+            Cow::Owned(vec![0; chr_ram.max(0x2000)])
+        } else {
+            Cow::Borrowed(chr_rom)
+        };
+        Self {
+            prg_rom,
+            chr,
+            sram: [0; 0x2000],
+            sram_enabled: true,
+            sram_writable: true,
+            nametables: Nametables::new(mirror),
+
+            select: 0,
+            banks: [0, 2, 4, 5, 6, 7, 0, 1],
+
+            irq: Counter {
+                alternate,
+                ..Counter::default()
+            },
+            a12: false,
+            a12_low: 0,
+        }
+    }
+
+    fn prg_addr(&self, idx: u16) -> usize {
+        let len = self.prg_rom.len();
+        let swap = self.select & 0x40 != 0;
+        let base = match (usize::from(idx - 0x8000) / 0x2000, swap) {
+            (0, false) | (2, true) => usize::from(self.banks[6]) * 0x2000,
+            (1, _) => usize::from(self.banks[7]) * 0x2000,
+            (0, true) | (2, false) => len.saturating_sub(0x4000),
+            _ => len.saturating_sub(0x2000),
+        };
+        (base + usize::from(idx) % 0x2000) % len
+    }
+
+    fn chr_addr(&self, idx: VAddr) -> usize {
+        let idx = usize::from(idx.get()) ^ if self.select & 0x80 != 0 { 0x1000 } else { 0 };
+        // The first two registers pick 2KiB banks, ignoring their lowest bit.
+        let addr = match idx / 0x400 {
+            0 | 1 => usize::from(self.banks[0] & 0xFE) * 0x400 + idx % 0x800,
+            2 | 3 => usize::from(self.banks[1] & 0xFE) * 0x400 + idx % 0x800,
+            bank => usize::from(self.banks[bank - 2]) * 0x400 + idx % 0x400,
+        };
+        addr % self.chr.len()
+    }
+}
+
+impl Counter {
+    fn clock(&mut self) {
+        let old = self.counter;
+        if self.counter == 0 || self.reload {
+            self.counter = self.latch;
+        } else {
+            self.counter -= 1;
+        }
+        if self.counter == 0 && self.enabled && (!self.alternate || old != 0 || self.reload) {
+            self.pending = true;
+        }
+        self.reload = false;
+    }
+}
+
+impl<'a> Mapper for Mmc3<'a> {
+    fn get(&self, idx: u16) -> u8 {
+        match idx {
+            0x6000..=0x7fff if self.sram_enabled => self.sram[usize::from(idx) - 0x6000],
+            0x8000..=0xffff => self.prg_rom[self.prg_addr(idx)],
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, idx: u16, val: u8) {
+        let even = idx % 2 == 0;
+        match idx {
+            0x6000..=0x7fff => {
+                if self.sram_enabled && self.sram_writable {
+                    self.sram[usize::from(idx) - 0x6000] = val;
+                }
+            }
+            0x8000..=0x9fff if even => self.select = val,
+            0x8000..=0x9fff => self.banks[usize::from(self.select & 0x07)] = val,
+            0xa000..=0xbfff if even => {
+                self.nametables.set_mirror(if val & 1 == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                });
+            }
+            0xa000..=0xbfff => {
+                self.sram_enabled = val & 0x80 != 0;
+                self.sram_writable = val & 0x40 == 0;
+            }
+            0xc000..=0xdfff if even => self.irq.latch = val,
+            0xc000..=0xdfff => {
+                self.irq.counter = 0;
+                self.irq.reload = true;
+            }
+            0xe000..=0xffff if even => {
+                self.irq.enabled = false;
+                self.irq.pending = false;
+            }
+            0xe000..=0xffff => self.irq.enabled = true,
+            _ => (),
+        }
+    }
+
+    fn get_ppu(&self, idx: VAddr) -> u8 { self.chr[self.chr_addr(idx)] }
+
+    fn set_ppu(&mut self, idx: VAddr, val: u8) {
+        let addr = self.chr_addr(idx);
+        if let Cow::Owned(chr) = &mut self.chr {
+            chr[addr] = val;
+        }
+    }
+
+    fn mirror<'nt>(&'nt self, vram: &'nt [Nametable; 2]) -> [&'nt Nametable; 4] {
+        self.nametables.arrange(vram)
+    }
+
+    fn irq(&self) -> bool { self.irq.pending }
+
+    // The counter is clocked when A12 rises, but only after it's been low for a few CPU cycles, so
+    // the sprite fetches at the end of each line only count once.
+    fn snoop(&mut self, addr: VAddr) {
+        let a12 = addr.get() & 0x1000 != 0;
+        if a12 && !self.a12 && self.a12_low >= 3 {
+            self.irq.clock();
+        }
+        if !a12 && self.a12 {
+            self.a12_low = 0;
+        }
+        self.a12 = a12;
+    }
+
+    fn clock(&mut self) {
+        if !self.a12 {
+            self.a12_low = self.a12_low.saturating_add(1);
+        }
+    }
+
+    fn sram(&self) -> Option<&[u8]> { Some(&self.sram) }
+
+    fn sram_mut(&mut self) -> Option<&mut [u8]> { Some(&mut self.sram) }
+}
+
+impl<'a> Snapshot for Mmc3<'a> {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(&self.sram);
+        if let Cow::Owned(chr) = &self.chr {
+            w.bytes(chr);
+        }
+        w.bool(self.sram_enabled);
+        w.bool(self.sram_writable);
+        self.nametables.save(w);
+
+        w.u8(self.select);
+        w.bytes(&self.banks);
+
+        w.u8(self.irq.latch);
+        w.u8(self.irq.counter);
+        w.bool(self.irq.reload);
+        w.bool(self.irq.enabled);
+        w.bool(self.irq.pending);
+        w.bool(self.a12);
+        w.u8(self.a12_low);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        r.bytes(&mut self.sram)?;
+        if let Cow::Owned(chr) = &mut self.chr {
+            r.bytes(chr)?;
+        }
+        self.sram_enabled = r.bool()?;
+        self.sram_writable = r.bool()?;
+        self.nametables.load(r)?;
+
+        self.select = r.u8()?;
+        r.bytes(&mut self.banks)?;
+
+        self.irq.latch = r.u8()?;
+        self.irq.counter = r.u8()?;
+        self.irq.reload = r.bool()?;
+        self.irq.enabled = r.bool()?;
+        self.irq.pending = r.bool()?;
+        self.a12 = r.bool()?;
+        self.a12_low = r.u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,438 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+
+use super::Mapper;
+use crate::ppu::{Nametable, VAddr};
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
+pub struct Mmc5<'a> {
+    prg_rom: &'a [u8],
+    chr: Cow<'a, [u8]>,
+    ram: Vec<u8>,
+    exram: [u8; 0x400],
+
+    prg_mode: u8,
+    chr_mode: u8,
+    // Writes to PRG RAM need these to be 2 and 1.
+    ram_protect: [u8; 2],
+    // 0 and 1 use ExRAM as a nametable, with 1 also as extended attributes, and 2 and 3 as RAM.
+    exram_mode: u8,
+    // Two bits for each nametable, picking VRAM, ExRAM or the fill tile.
+    nametables: u8,
+    fill_tile: u8,
+    fill_color: u8,
+
+    // $5113-$5117. Bit 7 picks ROM over RAM, though $5113 is always RAM and $5117 always ROM.
+    prg_banks: [u8; 5],
+    // $5120-$5127 for sprites, then $5128-$512B for the background. Only 8x16 sprites use both
+    // sets, otherwise it's whichever was written last.
+    chr_banks: [u16; 12],
+    chr_upper: u8,
+    last_background: bool,
+    tall_sprites: bool,
+
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+
+    irq_line: u8,
+    irq_enabled: bool,
+    // Cleared by reading $5204.
+    irq_pending: Cell<bool>,
+    multiplier: [u8; 2],
+
+    // Lines are spotted by the PPU reading the same nametable byte three times in a row, after
+    // which the fetches are counted to tell the background from sprites.
+    in_frame: bool,
+    scanline: u8,
+    last_fetch: u16,
+    repeats: u8,
+    fetches: u8,
+    idle: u8,
+    fetch: Fetch,
+    // Taken at the nametable fetch for the tile in progress.
+    in_split: bool,
+    split_y: u8,
+    ex_attribute: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Fetch {
+    // Outside of rendering, through $2007.
+    Cpu,
+    // The tile's position in its line.
+    Background(u8),
+    Sprite,
+}
+
+impl<'a> Mmc5<'a> {
+    // Boards without CHR ROM have RAM in its place instead.
+    pub fn new(prg_rom: &'a [u8], chr_rom: &'a [u8], chr_ram: usize, prg_ram: usize) -> Self {
+        let chr = if chr_rom.is_empty() {
+            Cow::Owned(vec![0; chr_ram.max(0x2000)])
+        } else {
+            Cow::Borrowed(chr_rom)
+        };
+        Self {
+            prg_rom,
+            chr,
+            ram: vec![0; prg_ram.max(0x2000)],
+            exram: [0; 0x400],
+
+            prg_mode: 3,
+            chr_mode: 0,
+            ram_protect: [0; 2],
+            exram_mode: 0,
+            nametables: 0,
+            fill_tile: 0,
+            fill_color: 0,
+
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            last_background: false,
+            tall_sprites: false,
+
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+
+            irq_line: 0,
+            irq_enabled: false,
+            irq_pending: Cell::new(false),
+            multiplier: [0xFF; 2],
+
+            in_frame: false,
+            scanline: 0,
+            last_fetch: 0,
+            repeats: 0,
+            fetches: 0,
+            idle: 0,
+            fetch: Fetch::Cpu,
+            in_split: false,
+            split_y: 0,
+            ex_attribute: 0,
+        }
+    }
+
+    // Whether $8000-$FFFF is mapped to ROM, and the address within it.
+    fn prg_addr(&self, idx: u16) -> (bool, usize) {
+        let slot = usize::from(idx - 0x8000) / 0x2000;
+        // The register for the slot, and the size of its bank in 8KiB pages.
+        let (reg, pages) = match self.prg_mode {
+            0 => (4, 4),
+            1 => (if slot < 2 { 2 } else { 4 }, 2),
+            2 if slot < 2 => (2, 2),
+            _ => (slot + 1, 1),
+        };
+        let bank = self.prg_banks[reg];
+        let page = usize::from(bank & 0x7F) & !(pages - 1) | slot & (pages - 1);
+        (reg == 4 || bank & 0x80 != 0, page * 0x2000 + usize::from(idx) % 0x2000)
+    }
+
+    fn ram_addr(&self, page: u8, idx: u16) -> usize {
+        (usize::from(page & 0x7F) * 0x2000 + usize::from(idx) % 0x2000) % self.ram.len()
+    }
+
+    fn ram_writable(&self) -> bool { self.ram_protect == [2, 1] }
+
+    fn chr_addr(&self, idx: VAddr, background: bool) -> usize {
+        let idx = usize::from(idx.get());
+        // In KiB
+        let size = 8 >> self.chr_mode;
+        let reg = (idx / 0x400) | (size - 1);
+        let bank = if background {
+            self.chr_banks[8 + (reg & 3)]
+        } else {
+            self.chr_banks[reg]
+        };
+        usize::from(bank) * size * 0x400 + idx % (size * 0x400)
+    }
+
+    fn split(&self, tile: u8) -> bool {
+        let threshold = self.split_control & 0x1F;
+        let right = self.split_control & 0x40 != 0;
+        self.split_control & 0x80 != 0 && self.exram_mode <= 1 && (tile >= threshold) == right
+    }
+}
+
+impl<'a> Mapper for Mmc5<'a> {
+    fn get(&self, idx: u16) -> u8 {
+        match idx {
+            0x5204 => (self.irq_pending.take() as u8) << 7 | (self.in_frame as u8) << 6,
+            0x5205 => (u16::from(self.multiplier[0]) * u16::from(self.multiplier[1])) as u8,
+            0x5206 => ((u16::from(self.multiplier[0]) * u16::from(self.multiplier[1])) >> 8) as u8,
+            0x5c00..=0x5fff if self.exram_mode >= 2 => self.exram[usize::from(idx) - 0x5c00],
+            0x6000..=0x7fff => self.ram[self.ram_addr(self.prg_banks[0], idx)],
+            0x8000..=0xffff => match self.prg_addr(idx) {
+                (true, addr) => self.prg_rom[addr % self.prg_rom.len()],
+                (false, addr) => self.ram[addr % self.ram.len()],
+            },
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, idx: u16, val: u8) {
+        match idx {
+            // Watched for the sprite size and rendering being turned off.
+            0x2000..=0x3fff if idx % 8 == 0 => self.tall_sprites = val & 0x20 != 0,
+            0x2000..=0x3fff if idx % 8 == 1 && val & 0x18 == 0 => self.in_frame = false,
+            0x5100 => self.prg_mode = val & 3,
+            0x5101 => self.chr_mode = val & 3,
+            0x5102 | 0x5103 => self.ram_protect[usize::from(idx - 0x5102)] = val & 3,
+            0x5104 => self.exram_mode = val & 3,
+            0x5105 => self.nametables = val,
+            0x5106 => self.fill_tile = val,
+            0x5107 => self.fill_color = val & 3,
+            0x5113..=0x5117 => self.prg_banks[usize::from(idx - 0x5113)] = val,
+            0x5120..=0x512b => {
+                let bank = u16::from(val) | u16::from(self.chr_upper) << 8;
+                self.chr_banks[usize::from(idx - 0x5120)] = bank;
+                self.last_background = idx >= 0x5128;
+            }
+            0x5130 => self.chr_upper = val & 3,
+            0x5200 => self.split_control = val,
+            0x5201 => self.split_scroll = val,
+            0x5202 => self.split_bank = val,
+            0x5203 => self.irq_line = val,
+            0x5204 => self.irq_enabled = val & 0x80 != 0,
+            0x5205 | 0x5206 => self.multiplier[usize::from(idx - 0x5205)] = val,
+            0x5c00..=0x5fff => {
+                let addr = usize::from(idx) - 0x5c00;
+                match self.exram_mode {
+                    // Only writable while rendering when used as a nametable.
+                    0 | 1 => self.exram[addr] = if self.in_frame { val } else { 0 },
+                    2 => self.exram[addr] = val,
+                    _ => (),
+                }
+            }
+            0x6000..=0x7fff if self.ram_writable() => {
+                let addr = self.ram_addr(self.prg_banks[0], idx);
+                self.ram[addr] = val;
+            }
+            0x8000..=0xdfff if self.ram_writable() => {
+                if let (false, addr) = self.prg_addr(idx) {
+                    let len = self.ram.len();
+                    self.ram[addr % len] = val;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn get_ppu(&self, idx: VAddr) -> u8 {
+        let background = match self.fetch {
+            Fetch::Background(_) if self.in_split => {
+                // The split has its own fine scroll.
+                let addr = usize::from(idx.get()) & 0xFF8 | usize::from(self.split_y % 8);
+                return self.chr[(usize::from(self.split_bank) * 0x1000 + addr) % self.chr.len()];
+            }
+            Fetch::Background(_) if self.exram_mode == 1 => {
+                let bank = usize::from(self.ex_attribute & 0x3F) | usize::from(self.chr_upper) << 6;
+                let addr = bank * 0x1000 + usize::from(idx.get() & 0xFFF);
+                return self.chr[addr % self.chr.len()];
+            }
+            Fetch::Background(_) if self.tall_sprites => true,
+            Fetch::Sprite if self.tall_sprites => false,
+            _ => self.last_background,
+        };
+        self.chr[self.chr_addr(idx, background) % self.chr.len()]
+    }
+
+    fn set_ppu(&mut self, idx: VAddr, val: u8) {
+        let addr = self.chr_addr(idx, self.last_background) % self.chr.len();
+        if let Cow::Owned(chr) = &mut self.chr {
+            chr[addr] = val;
+        }
+    }
+
+    // Only for the nametables in VRAM, the others are handled by `get_nametable`.
+    fn mirror<'nt>(&'nt self, vram: &'nt [Nametable; 2]) -> [&'nt Nametable; 4] {
+        let nt = |i: u8| &vram[usize::from(self.nametables >> (i * 2) & 1)];
+        [nt(0), nt(1), nt(2), nt(3)]
+    }
+
+    fn get_nametable(&self, addr: VAddr, vram: &[Nametable; 2]) -> u8 {
+        let attribute = self.fetches % 4 == 1;
+        if let Fetch::Background(tile) = self.fetch {
+            let tile = usize::from(tile % 32);
+            if self.in_split {
+                let row = usize::from(self.split_y / 8);
+                if !attribute {
+                    return self.exram[row * 32 + tile];
+                }
+                let attr = self.exram[0x3C0 + row / 4 * 8 + tile / 4];
+                return (attr >> ((row & 2) << 1 | (tile & 2)) & 3) * 0x55;
+            }
+            if self.exram_mode == 1 && attribute {
+                return (self.ex_attribute >> 6) * 0x55;
+            }
+        }
+
+        let offset = addr.get() % 0x400;
+        match (self.nametables >> (((addr.get() >> 10) & 3) * 2)) & 3 {
+            0 => vram[0].read(offset),
+            1 => vram[1].read(offset),
+            2 if self.exram_mode <= 1 => self.exram[usize::from(offset)],
+            2 => 0,
+            _ if offset >= 0x3C0 => self.fill_color * 0x55,
+            _ => self.fill_tile,
+        }
+    }
+
+    fn set_nametable(&mut self, addr: VAddr, val: u8, vram: &[Nametable; 2]) {
+        let offset = addr.get() % 0x400;
+        match (self.nametables >> (((addr.get() >> 10) & 3) * 2)) & 3 {
+            0 => vram[0].write(offset, val),
+            1 => vram[1].write(offset, val),
+            2 if self.exram_mode <= 1 => self.exram[usize::from(offset)] = val,
+            _ => (),
+        }
+    }
+
+    fn irq(&self) -> bool { self.irq_pending.get() && self.irq_enabled }
+
+    fn snoop(&mut self, addr: VAddr) {
+        let addr = addr.get();
+        self.idle = 0;
+        self.repeats = if addr == self.last_fetch { self.repeats.saturating_add(1) } else { 0 };
+        self.last_fetch = addr;
+
+        if self.repeats == 2 && (0x2000..0x3000).contains(&addr) {
+            if self.in_frame {
+                self.scanline = self.scanline.wrapping_add(1);
+                if self.scanline == self.irq_line {
+                    self.irq_pending.set(true);
+                }
+            } else {
+                self.in_frame = true;
+                self.scanline = 0;
+            }
+            self.fetches = 0;
+        } else {
+            self.fetches = self.fetches.saturating_add(1);
+        }
+
+        // Each tile takes four fetches, the first two tiles of a line having been fetched at the
+        // end of the last one, after the sprites.
+        let (fetch, next_line) = match self.fetches {
+            _ if !self.in_frame => (Fetch::Cpu, 0),
+            0..=127 => (Fetch::Background(self.fetches / 4 + 2), 0),
+            128..=143 => (Fetch::Sprite, 0),
+            144..=151 => (Fetch::Background((self.fetches - 144) / 4), 1),
+            _ => (Fetch::Background(34), 1),
+        };
+        self.fetch = fetch;
+        if let (Fetch::Background(tile), 0) = (fetch, self.fetches % 4) {
+            let y = (u16::from(self.split_scroll) + u16::from(self.scanline) + next_line) % 240;
+            self.split_y = y as u8;
+            self.in_split = self.split(tile);
+            self.ex_attribute = self.exram[usize::from(addr % 0x400)];
+        }
+    }
+
+    // The PPU stops reading once it's finished the picture.
+    fn clock(&mut self) {
+        self.idle = self.idle.saturating_add(1);
+        if self.idle >= 3 {
+            self.in_frame = false;
+        }
+    }
+
+    fn sram(&self) -> Option<&[u8]> { Some(&self.ram) }
+
+    fn sram_mut(&mut self) -> Option<&mut [u8]> { Some(&mut self.ram) }
+}
+
+impl<'a> Snapshot for Mmc5<'a> {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(&self.ram);
+        w.bytes(&self.exram);
+        if let Cow::Owned(chr) = &self.chr {
+            w.bytes(chr);
+        }
+
+        w.u8(self.prg_mode);
+        w.u8(self.chr_mode);
+        w.bytes(&self.ram_protect);
+        w.u8(self.exram_mode);
+        w.u8(self.nametables);
+        w.u8(self.fill_tile);
+        w.u8(self.fill_color);
+        w.bytes(&self.prg_banks);
+        self.chr_banks.iter().for_each(|&bank| w.u16(bank));
+        w.u8(self.chr_upper);
+        w.bool(self.last_background);
+        w.bool(self.tall_sprites);
+
+        w.u8(self.split_control);
+        w.u8(self.split_scroll);
+        w.u8(self.split_bank);
+        w.u8(self.irq_line);
+        w.bool(self.irq_enabled);
+        w.bool(self.irq_pending.get());
+        w.bytes(&self.multiplier);
+
+        w.bool(self.in_frame);
+        w.u8(self.scanline);
+        w.u16(self.last_fetch);
+        w.u8(self.repeats);
+        w.u8(self.fetches);
+        w.u8(self.idle);
+        w.u8(match self.fetch {
+            Fetch::Cpu => 0xFF,
+            Fetch::Sprite => 0xFE,
+            Fetch::Background(tile) => tile,
+        });
+        w.bool(self.in_split);
+        w.u8(self.split_y);
+        w.u8(self.ex_attribute);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        r.bytes(&mut self.ram)?;
+        r.bytes(&mut self.exram)?;
+        if let Cow::Owned(chr) = &mut self.chr {
+            r.bytes(chr)?;
+        }
+
+        self.prg_mode = r.u8()? & 3;
+        self.chr_mode = r.u8()? & 3;
+        r.bytes(&mut self.ram_protect)?;
+        self.exram_mode = r.u8()? & 3;
+        self.nametables = r.u8()?;
+        self.fill_tile = r.u8()?;
+        self.fill_color = r.u8()? & 3;
+        r.bytes(&mut self.prg_banks)?;
+        for bank in &mut self.chr_banks {
+            *bank = r.u16()?;
+        }
+        self.chr_upper = r.u8()? & 3;
+        self.last_background = r.bool()?;
+        self.tall_sprites = r.bool()?;
+
+        self.split_control = r.u8()?;
+        self.split_scroll = r.u8()?;
+        self.split_bank = r.u8()?;
+        self.irq_line = r.u8()?;
+        self.irq_enabled = r.bool()?;
+        self.irq_pending.set(r.bool()?);
+        r.bytes(&mut self.multiplier)?;
+
+        self.in_frame = r.bool()?;
+        self.scanline = r.u8()?;
+        self.last_fetch = r.u16()?;
+        self.repeats = r.u8()?;
+        self.fetches = r.u8()?;
+        self.idle = r.u8()?;
+        self.fetch = match r.u8()? {
+            0xFF => Fetch::Cpu,
+            0xFE => Fetch::Sprite,
+            tile => Fetch::Background(tile),
+        };
+        self.in_split = r.bool()?;
+        self.split_y = r.u8()?;
+        self.ex_attribute = r.u8()?;
+        Ok(())
+    }
+}
@@ -1,25 +1,36 @@
+use std::borrow::Cow;
+
 use crate::ines::Mirroring;
-use crate::ppu::pattern::{PTIdx, PatternTableRef};
+use super::{Mapper, Nametables};
 use crate::ppu::{Nametable, VAddr};
+use crate::state::{Reader, Snapshot, StateError, Writer};
 
 pub struct NRom<'a> {
     prg_rom: &'a [u8],
-    chr_rom: &'a [u8],
+    chr: Cow<'a, [u8]>,
     sram: [u8; 0x2000],
-    mirror: Mirroring,
+    nametables: Nametables,
 }
 
 impl<'a> NRom<'a> {
-    pub const fn new(prg_rom: &'a [u8], chr_rom: &'a [u8], mirror: Mirroring) -> Self {
+    // Boards without CHR ROM have RAM in its place instead.
+    pub fn new(prg_rom: &'a [u8], chr_rom: &'a [u8], chr_ram: usize, mirror: Mirroring) -> Self {
+        let chr = if chr_rom.is_empty() {
+            Cow::Owned(vec![0; chr_ram.max(0x2000)])
+        } else {
+            Cow::Borrowed(chr_rom)
+        };
         Self {
             prg_rom,
-            chr_rom,
+            chr,
             sram: [0; 0x2000],
-            mirror,
+            nametables: Nametables::new(mirror),
         }
     }
+}
 
-    pub fn get(&self, idx: u16) -> u8 {
+impl<'a> Mapper for NRom<'a> {
+    fn get(&self, idx: u16) -> u8 {
         match idx {
             0x6000..=0x7fff => self.sram[usize::from(idx) - 0x6000],
             0x8000..=0xffff => self.prg_rom[(usize::from(idx) - 0x8000) % self.prg_rom.len()],
@@ -27,29 +38,44 @@ impl<'a> NRom<'a> {
         }
     }
 
-    pub fn get_pattern_table(&'a self, idx: PTIdx) -> PatternTableRef<'a> {
-        let (tables, rest) = self.chr_rom.as_chunks();
-        debug_assert_eq!(rest.len(), 0);
-        match idx {
-            PTIdx::Left => PatternTableRef(&tables[0]),
-            PTIdx::Right => PatternTableRef(&tables[1]),
+    fn sram(&self) -> Option<&[u8]> { Some(&self.sram) }
+
+    fn sram_mut(&mut self) -> Option<&mut [u8]> { Some(&mut self.sram) }
+
+    fn get_ppu(&self, idx: VAddr) -> u8 { self.chr[usize::from(idx.get())] }
+
+    fn set_ppu(&mut self, idx: VAddr, val: u8) {
+        if let Cow::Owned(chr) = &mut self.chr {
+            chr[usize::from(idx.get())] = val;
         }
     }
 
-    pub fn get_ppu(&self, idx: VAddr) -> u8 { self.chr_rom[usize::from(idx.get())] }
-
-    pub fn set(&mut self, idx: u16, val: u8) {
+    fn set(&mut self, idx: u16, val: u8) {
         match idx {
             0x6000..=0x7fff => self.sram[usize::from(idx) - 0x6000] = val,
             _ => (),
         }
     }
 
-    pub fn mirror<'nt>(&self, vram: &'nt [Nametable; 2]) -> [&'nt Nametable; 4] {
-        match self.mirror {
-            Mirroring::Horizontal => [&vram[0], &vram[0], &vram[1], &vram[1]],
-            Mirroring::Vertical => [&vram[0], &vram[1], &vram[0], &vram[1]],
-            Mirroring::Ignore => [&vram[0], &vram[0], &vram[0], &vram[0]],
+    fn mirror<'nt>(&'nt self, vram: &'nt [Nametable; 2]) -> [&'nt Nametable; 4] {
+        self.nametables.arrange(vram)
+    }
+}
+
+impl<'a> Snapshot for NRom<'a> {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(&self.sram);
+        if let Cow::Owned(chr) = &self.chr {
+            w.bytes(chr);
+        }
+        self.nametables.save(w);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        r.bytes(&mut self.sram)?;
+        if let Cow::Owned(chr) = &mut self.chr {
+            r.bytes(chr)?;
         }
+        self.nametables.load(r)
     }
 }
@@ -0,0 +1,157 @@
+use std::borrow::Cow;
+
+use super::Mapper;
+use crate::ppu::{Nametable, VAddr};
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
+// Mapper 68, which can put 1KiB pages of CHR ROM in place of the nametables.
+pub struct Sunsoft4<'a> {
+    prg_rom: &'a [u8],
+    chr: Cow<'a, [u8]>,
+    sram: [u8; 0x2000],
+    sram_enabled: bool,
+
+    prg_bank: u8,
+    chr_banks: [u8; 4],
+    nt_banks: [u8; 2],
+    // The low 2 bits pick the mirroring, and bit 4 swaps the console's nametables for CHR ROM.
+    control: u8,
+}
+
+impl<'a> Sunsoft4<'a> {
+    // Boards without CHR ROM have RAM in its place instead.
+    pub fn new(prg_rom: &'a [u8], chr_rom: &'a [u8], chr_ram: usize) -> Self {
+        let chr = if chr_rom.is_empty() {
+            Cow::Owned(vec![0; chr_ram.max(0x2000)])
+        } else {
+            Cow::Borrowed(chr_rom)
+        };
+        Self {
+            prg_rom,
+            chr,
+            sram: [0; 0x2000],
+            sram_enabled: false,
+
+            prg_bank: 0,
+            chr_banks: [0; 4],
+            nt_banks: [0; 2],
+            control: 0,
+        }
+    }
+
+    fn prg_addr(&self, idx: u16) -> usize {
+        let idx = usize::from(idx) - 0x8000;
+        let addr = if idx < 0x4000 {
+            usize::from(self.prg_bank) * 0x4000 + idx
+        } else {
+            self.prg_rom.len().saturating_sub(0x4000) + idx % 0x4000
+        };
+        addr % self.prg_rom.len()
+    }
+
+    fn chr_addr(&self, idx: VAddr) -> usize {
+        let idx = usize::from(idx.get());
+        (usize::from(self.chr_banks[idx / 0x800]) * 0x800 + idx % 0x800) % self.chr.len()
+    }
+
+    // Which of the two nametables, or nametable registers, is at the given address.
+    fn table(&self, addr: u16) -> usize {
+        let quadrant = usize::from(addr >> 10) % 4;
+        match self.control & 0x03 {
+            0 => quadrant & 1,
+            1 => quadrant >> 1,
+            single => usize::from(single) - 2,
+        }
+    }
+}
+
+impl<'a> Mapper for Sunsoft4<'a> {
+    fn get(&self, idx: u16) -> u8 {
+        match idx {
+            0x6000..=0x7fff if self.sram_enabled => self.sram[usize::from(idx) - 0x6000],
+            0x8000..=0xffff => self.prg_rom[self.prg_addr(idx)],
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, idx: u16, val: u8) {
+        match idx {
+            0x6000..=0x7fff => {
+                if self.sram_enabled {
+                    self.sram[usize::from(idx) - 0x6000] = val;
+                }
+            }
+            0x8000..=0xbfff => self.chr_banks[usize::from(idx >> 12) - 8] = val,
+            // Only the upper 128KiB of CHR ROM can be used as nametables.
+            0xc000..=0xdfff => self.nt_banks[usize::from(idx >> 12) - 0xc] = val | 0x80,
+            0xe000..=0xefff => self.control = val,
+            0xf000..=0xffff => {
+                self.prg_bank = val & 0x0f;
+                self.sram_enabled = val & 0x10 != 0;
+            }
+            _ => (),
+        }
+    }
+
+    fn get_ppu(&self, idx: VAddr) -> u8 { self.chr[self.chr_addr(idx)] }
+
+    fn set_ppu(&mut self, idx: VAddr, val: u8) {
+        let addr = self.chr_addr(idx);
+        if let Cow::Owned(chr) = &mut self.chr {
+            chr[addr] = val;
+        }
+    }
+
+    fn mirror<'nt>(&'nt self, vram: &'nt [Nametable; 2]) -> [&'nt Nametable; 4] {
+        let nt = |i: u16| &vram[self.table(i * 0x400)];
+        [nt(0), nt(1), nt(2), nt(3)]
+    }
+
+    fn get_nametable(&self, addr: VAddr, vram: &[Nametable; 2]) -> u8 {
+        if self.control & 0x10 == 0 {
+            return vram[self.table(addr.get())].read(addr.get() % 0x400);
+        }
+        let page = usize::from(self.nt_banks[self.table(addr.get())]);
+        self.chr[(page * 0x400 + usize::from(addr.get() % 0x400)) % self.chr.len()]
+    }
+
+    // CHR ROM can't be written, so writes only land while the console's nametables are used.
+    fn set_nametable(&mut self, addr: VAddr, val: u8, vram: &[Nametable; 2]) {
+        if self.control & 0x10 == 0 {
+            vram[self.table(addr.get())].write(addr.get() % 0x400, val);
+        }
+    }
+
+    fn sram(&self) -> Option<&[u8]> { Some(&self.sram) }
+
+    fn sram_mut(&mut self) -> Option<&mut [u8]> { Some(&mut self.sram) }
+}
+
+impl<'a> Snapshot for Sunsoft4<'a> {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(&self.sram);
+        if let Cow::Owned(chr) = &self.chr {
+            w.bytes(chr);
+        }
+        w.bool(self.sram_enabled);
+
+        w.u8(self.prg_bank);
+        w.bytes(&self.chr_banks);
+        w.bytes(&self.nt_banks);
+        w.u8(self.control);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        r.bytes(&mut self.sram)?;
+        if let Cow::Owned(chr) = &mut self.chr {
+            r.bytes(chr)?;
+        }
+        self.sram_enabled = r.bool()?;
+
+        self.prg_bank = r.u8()?;
+        r.bytes(&mut self.chr_banks)?;
+        r.bytes(&mut self.nt_banks)?;
+        self.control = r.u8()?;
+        Ok(())
+    }
+}
@@ -1,13 +1,14 @@
 use std::time::Duration;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 use image::{Bgra, DynamicImage, GenericImage, ImageBuffer, Luma};
-use minifb::{Result, Scale, ScaleMode, Window, WindowOptions};
+use minifb::{Key, Result, Scale, ScaleMode, Window, WindowOptions};
 
 use super::pattern::{PTIdx, PatternTableRef, PatternTile};
 use super::{Nametable, PixelCoord, Point, Vram};
+use crate::input::Buttons;
 use crate::memory::Cartridge;
 
 pub struct Ppu {
@@ -16,7 +17,7 @@ pub struct Ppu {
 }
 
 impl Ppu {
-    pub fn open(fb: Arc<Mutex<ImageBuffer<Bgra<u8>, Vec<u8>>>>) -> Arc<AtomicBool> {
+    pub fn open(fb: Arc<Mutex<ImageBuffer<Bgra<u8>, Vec<u8>>>>, input: Arc<AtomicU8>) -> Arc<AtomicBool> {
         let running = Arc::new(AtomicBool::new(true));
         let res = running.clone();
 
@@ -30,6 +31,17 @@ impl Ppu {
 
             while win.is_open() {
                 thread::sleep(Duration::from_secs(1) / 60);
+                let buttons = Buttons {
+                    a: win.is_key_down(Key::X),
+                    b: win.is_key_down(Key::Z),
+                    select: win.is_key_down(Key::RightShift),
+                    start: win.is_key_down(Key::Enter),
+                    up: win.is_key_down(Key::Up),
+                    down: win.is_key_down(Key::Down),
+                    left: win.is_key_down(Key::Left),
+                    right: win.is_key_down(Key::Right),
+                };
+                input.store(buttons.into(), Ordering::Relaxed);
                 let fb = fb.lock().unwrap();
                 win.update_with_buffer(
                     unsafe { &*(fb.as_chunks().0 as *const [[u8; 4]] as *const [u32]) },
@@ -1,4 +1,5 @@
 use super::{NTAddr, PixelCoord, Point, TileCoord, VAddr};
+use crate::state::{Reader, Snapshot, StateError, Writer};
 
 #[derive(Debug, Copy, Clone)]
 pub struct AddrReg {
@@ -145,3 +146,20 @@ impl AddrReg {
 
     pub fn get_fine_x(&self) -> PixelCoord { self.fine_x }
 }
+
+impl Snapshot for AddrReg {
+    fn save(&self, w: &mut Writer) {
+        w.u16(self.address);
+        w.u16(self.temp);
+        w.u8(self.fine_x.get());
+        w.bool(self.latch == AddrLatch::Low);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.address = r.u16()? & 0x7FFF;
+        self.temp = r.u16()? & 0x7FFF;
+        self.fine_x = new_wrapping!(PixelCoord, r.u8()?);
+        self.latch = if r.bool()? { AddrLatch::Low } else { AddrLatch::High };
+        Ok(())
+    }
+}
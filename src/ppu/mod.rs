@@ -2,6 +2,7 @@ use std::rc::Rc;
 use bounded_integer::bounded_integer;
 
 use crate::memory::Cartridge;
+use crate::state::{Reader, Snapshot, StateError, Writer};
 
 #[cfg(feature = "minifb")]
 pub mod backend;
@@ -12,6 +13,7 @@ mod palette;
 pub mod pattern;
 mod regs;
 pub mod render;
+mod sprite;
 
 pub use loopy::AddrReg;
 use loopy::Time;
@@ -27,7 +29,7 @@ bounded_integer!(pub enum PixelCoord { 0..8 });
 bounded_integer!(pub enum TileCoord { 0..32 });
 
 pub struct Vram {
-    oam: Oam,
+    pub oam: Rc<Oam>,
     pub palette: PaletteRam,
     pub vram: [Nametable; 2],
     pub registers: Rc<Registers>,
@@ -81,7 +83,7 @@ impl NTAddr {
 impl Vram {
     pub fn new() -> Self {
         Vram {
-            oam: Oam::new(),
+            oam: Rc::new(Oam::new()),
             palette: PaletteRam::default(),
             vram: [Nametable::new(), Nametable::new()],
             registers: Rc::new(Registers::default()),
@@ -90,25 +92,35 @@ impl Vram {
         }
     }
 
-    pub fn get_ppu<'c>(&self, addr: VAddr, cart: &Cartridge<'c>) -> u8 {
+    // Memory is left as is, only the registers and read buffer are cleared.
+    pub fn reset(&mut self) {
+        self.registers.reset();
+        self.data_bus = 0;
+    }
+
+    pub fn power_on(&mut self) {
+        self.registers.power_on();
+        self.oam.clear();
+        self.palette = PaletteRam::default();
+        self.vram = [Nametable::new(), Nametable::new()];
+        self.data_bus = 0;
+    }
+
+    pub fn get_ppu<'c>(&self, addr: VAddr, cart: &mut Cartridge<'c>) -> u8 {
+        cart.snoop(addr);
         match addr.get() {
             0x0000..=0x1FFF => cart.get_ppu(addr),
-            0x2000..=0x3EFF => {
-                cart.mirror(&self.vram)[usize::from(addr.get() >> 10) % 4].read(addr.get() % 0x400)
-            }
+            0x2000..=0x3EFF => cart.get_nametable(addr, &self.vram),
             0x3F00..=0x3FFF => self.palette.read((addr.get() % 0x20) as u8),
             _ => unreachable!(),
         }
     }
 
     pub fn set_ppu<'c>(&mut self, addr: VAddr, val: u8, cart: &mut Cartridge<'c>) {
+        cart.snoop(addr);
         match addr.get() {
             0x0000..=0x1FFF => cart.set_ppu(addr, val),
-            0x2000..=0x3EFF => {
-                let nt = &cart.mirror(&mut self.vram)[usize::from(addr.get() >> 10) % 4];
-
-                nt.write(addr.get() % 0x400, val);
-            }
+            0x2000..=0x3EFF => cart.set_nametable(addr, val, &self.vram),
             0x3F00..=0x3FFF => self.palette.write((addr.get() % 0x20) as u8, val),
             _ => unreachable!(),
         }
@@ -122,6 +134,7 @@ impl Vram {
                 self.registers.set_vblank(false);
                 (s, None)
             }
+            4 => (self.oam.read_byte(self.registers.oam_addr.get()), None),
             7 => {
                 let addr = self.registers.advance_vaddr();
                 if addr >= 0x3F00 {
@@ -139,6 +152,12 @@ impl Vram {
         match addr.get() {
             0 => self.registers.set_control(val),
             1 => self.registers.mask.set(val.into()),
+            3 => self.registers.oam_addr.set(val),
+            4 => {
+                let addr = self.registers.oam_addr.get();
+                self.oam.write_byte(val, addr);
+                self.registers.oam_addr.set(addr.wrapping_add(1));
+            }
             5 => {
                 self.registers.addr.update(|a| a.write_scroll(val, Time::Delayed));
             },
@@ -160,6 +179,27 @@ impl Vram {
     }
 }
 
+impl Snapshot for Vram {
+    fn save(&self, w: &mut Writer) {
+        self.registers.save(w);
+        self.oam.save(w);
+        self.palette.save(w);
+        self.vram.iter().for_each(|nt| nt.save(w));
+        w.u8(self.data_bus);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.registers.load(r)?;
+        self.oam.load(r)?;
+        self.palette.load(r)?;
+        for nt in self.vram.iter_mut() {
+            nt.load(r)?;
+        }
+        self.data_bus = r.u8()?;
+        Ok(())
+    }
+}
+
 pub struct Point<T> {
     x: T,
     y: T,
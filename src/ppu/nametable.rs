@@ -1,5 +1,7 @@
 use std::cell::Cell;
 
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
 pub const NAMETABLE_LEN: usize = 0x400;
 #[derive(Debug, Clone)]
 pub struct Nametable([Cell<u8>; NAMETABLE_LEN]);
@@ -15,3 +17,14 @@ impl Nametable {
 
     pub fn attr_table(&self) -> &[Cell<u8>; 64] { todo!() }
 }
+
+impl Snapshot for Nametable {
+    fn save(&self, w: &mut Writer) { self.0.iter().for_each(|b| w.u8(b.get())); }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        for b in self.0.iter_mut() {
+            *b.get_mut() = r.u8()?;
+        }
+        Ok(())
+    }
+}
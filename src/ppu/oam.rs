@@ -1,30 +1,110 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
 use bounded_integer::bounded_integer;
 
-pub struct Oam([Sprite; 64]);
+use super::palette::PaletteIdx;
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
+pub struct Oam(Cell<[Sprite; 64]>);
 
 bounded_integer!(pub struct OamIdx { 0..64 });
 
 #[derive(Debug, Copy, Clone, Default)]
 pub struct Sprite {
-    y: u8,
-    tile: u8,
-    attr: u8,
-    x: u8,
+    pub y: u8,
+    pub tile: u8,
+    pub attr: u8,
+    pub x: u8,
 }
 
-impl Oam {
-    pub fn new() -> Self { Oam([Sprite::default(); 64]) }
+impl Sprite {
+    pub const FILLER: Sprite = Sprite {
+        y: 0xFF,
+        tile: 0xFF,
+        attr: 0xFF,
+        x: 0xFF,
+    };
 
-    pub fn write_byte(&mut self, val: u8, idx: u8) {
-        let sprite = &mut self.0[usize::from(idx / 4)];
+    pub fn read_byte(&self, idx: u8) -> u8 {
         match idx % 4 {
-            0 => sprite.y = val,
-            1 => sprite.tile = val,
-            2 => sprite.attr = val,
-            3 => sprite.x = val,
+            0 => self.y,
+            1 => self.tile,
+            // Bits 2-4 of the attribute byte don't exist in OAM.
+            2 => self.attr & 0xE3,
+            3 => self.x,
             _ => unreachable!(),
         }
     }
 
-    pub fn get_sprite(&self, idx: OamIdx) -> Sprite { self.0[usize::from(idx.get())] }
+    pub fn write_byte(mut self, idx: u8, val: u8) -> Self {
+        match idx % 4 {
+            0 => self.y = val,
+            1 => self.tile = val,
+            2 => self.attr = val,
+            3 => self.x = val,
+            _ => unreachable!(),
+        }
+        self
+    }
+
+    pub fn palette(&self) -> PaletteIdx { new_wrapping!(PaletteIdx, self.attr) }
+
+    pub fn behind(&self) -> bool { self.attr & 0x20 != 0 }
+
+    pub fn flip_x(&self) -> bool { self.attr & 0x40 != 0 }
+
+    pub fn flip_y(&self) -> bool { self.attr & 0x80 != 0 }
+}
+
+impl Default for Oam {
+    fn default() -> Self { Oam(Cell::new([Sprite::default(); 64])) }
+}
+
+impl Oam {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn clear(&self) { self.0.set([Sprite::default(); 64]); }
+
+    pub fn write_byte(&self, val: u8, idx: u8) {
+        let sprite = &self.sprites()[usize::from(idx / 4)];
+        sprite.set(sprite.get().write_byte(idx, val));
+    }
+
+    pub fn read_byte(&self, idx: u8) -> u8 {
+        self.sprites()[usize::from(idx / 4)].get().read_byte(idx)
+    }
+
+    pub fn get_sprite(&self, idx: OamIdx) -> Sprite { self.sprites()[usize::from(idx.get())].get() }
+
+    fn sprites(&self) -> &[Cell<Sprite>] {
+        let sprites: &Cell<[Sprite]> = &self.0;
+        sprites.as_slice_of_cells()
+    }
+}
+
+impl Snapshot for Sprite {
+    fn save(&self, w: &mut Writer) { w.bytes(&[self.y, self.tile, self.attr, self.x]); }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        let mut bytes = [0; 4];
+        r.bytes(&mut bytes)?;
+        let [y, tile, attr, x] = bytes;
+        *self = Sprite { y, tile, attr, x };
+        Ok(())
+    }
+}
+
+// Shared with the renderer, so it's restored in place.
+impl Snapshot for Rc<Oam> {
+    fn save(&self, w: &mut Writer) { self.0.get().iter().for_each(|sprite| sprite.save(w)); }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        let mut sprites = [Sprite::default(); 64];
+        for sprite in sprites.iter_mut() {
+            sprite.load(r)?;
+        }
+        self.0.set(sprites);
+        Ok(())
+    }
 }
@@ -1,6 +1,8 @@
 use bounded_integer::bounded_integer;
 use image::Rgb;
 
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
 #[derive(Debug, Default)]
 pub struct PaletteRam {
     background: u8,
@@ -65,6 +67,23 @@ impl PaletteRam {
     }
 }
 
+impl Snapshot for PaletteRam {
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.background);
+        self.bg_palettes.iter().for_each(|p| w.bytes(p));
+        self.sprite_palettes.iter().for_each(|p| w.bytes(p));
+        w.bytes(&self.unused);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.background = r.u8()?;
+        for p in self.bg_palettes.iter_mut().chain(self.sprite_palettes.iter_mut()) {
+            r.bytes(p)?;
+        }
+        r.bytes(&mut self.unused)
+    }
+}
+
 impl ColorCode {
     pub fn as_rgb(self) -> Rgb<u8> { DEFAULT_PALETTE[usize::from(self.get())] }
 }
@@ -1,25 +1,27 @@
 use std::cell::Cell;
+use std::rc::Rc;
+
 use super::loopy::{AddrReg, Time};
 use super::pattern::PTIdx;
 use super::{NTAddr, VAddr, TileCoord, PixelCoord};
+use crate::state::{Reader, Snapshot, StateError, Writer};
 
 #[derive(Default, Debug, Clone)]
 pub struct Registers {
     pub control: Cell<Control>,
     pub mask: Cell<Mask>,
     pub status: Cell<Status>,
-    oam_addr: u8,
-    oam_data: u8,
+    pub oam_addr: Cell<u8>,
     pub addr: Cell<AddrReg>,
 }
 
-#[derive(Debug, Copy, Clone, Default)]
+#[derive(Debug, Copy, Clone)]
 pub struct Control {
     base_nt: NTAddr,
     vram_inc: u16,
-    sprite_table: PTIdx,
+    pub sprite_table: PTIdx,
     pub bg_table: PTIdx,
-    sprite_height: u8,
+    pub sprite_height: u8,
     interrupt: bool,
 }
 
@@ -33,10 +35,10 @@ pub struct Status {
 #[derive(Debug, Copy, Clone, Default)]
 pub struct Mask {
     color: Color,
-    background_left: Show,
-    sprites_left: Show,
+    pub background_left: Show,
+    pub sprites_left: Show,
     pub background: Show,
-    sprites: Show,
+    pub sprites: Show,
     red: Emphasis,
     green: Emphasis,
     blue: Emphasis,
@@ -63,6 +65,10 @@ enum Emphasis {
 impl Registers {
     pub fn set_vblank(&self, val: bool) { self.status.update(|s| Status{ vblank: val, ..s }); }
 
+    pub fn set_zero_hit(&self, val: bool) { self.status.update(|s| Status{ zero_hit: val, ..s }); }
+
+    pub fn set_overflow(&self, val: bool) { self.status.update(|s| Status{ overflow: val, ..s }); }
+
     pub fn advance_vaddr(&self) -> VAddr {
         let mut reg = self.addr.get();
         let addr = reg.advance(self.control.get().vram_inc);
@@ -70,6 +76,22 @@ impl Registers {
         addr
     }
 
+    // The reset line clears everything but the status flags and OAM address.
+    pub fn reset(&self) {
+        self.control.set(0.into());
+        self.mask.set(0.into());
+        self.addr.update(|mut a| {
+            a.reset();
+            a
+        });
+    }
+
+    pub fn power_on(&self) {
+        self.reset();
+        self.status.set(Status::default());
+        self.oam_addr.set(0);
+    }
+
     pub fn set_control(&self, val: u8) {
         let reg = val.into();
         self.control.set(reg);
@@ -85,6 +107,10 @@ impl Registers {
         self.control.get().interrupt
     }
 
+    pub fn nmi_output(&self) -> bool {
+        self.status.get().vblank && self.interrupt_enabled()
+    }
+
     pub fn increment_scrollx(&self) {
         if self.enabled() {
             let mut addr = self.addr.get();
@@ -96,6 +122,7 @@ impl Registers {
             } else {
                 addr.set_coarse_x(coarse_x + 1, Time::Immediate);
             }
+            self.addr.set(addr);
         }
     }
 
@@ -113,10 +140,11 @@ impl Registers {
                             addr.set_nametable(nt.flip_y(), Time::Immediate);
                         }
                         31 => addr.set_coarse_y(TileCoord::Z, Time::Immediate),
-                        _ => (),
+                        y => addr.set_coarse_y(new_wrapping!(TileCoord, y + 1), Time::Immediate),
                     }
                 }
             }
+            self.addr.set(addr);
         }
     }
 
@@ -137,6 +165,9 @@ impl Registers {
     }
 }
 
+impl Default for Control {
+    fn default() -> Self { Control::from(0) }
+}
 impl Default for Color {
     fn default() -> Self { Color::Normal }
 }
@@ -147,6 +178,30 @@ impl Default for Emphasis {
     fn default() -> Self { Emphasis::Off }
 }
 
+impl From<Control> for u8 {
+    fn from(c: Control) -> u8 {
+        c.base_nt.get()
+            | ((c.vram_inc == 32) as u8) << 2
+            | ((c.sprite_table == PTIdx::Right) as u8) << 3
+            | ((c.bg_table == PTIdx::Right) as u8) << 4
+            | ((c.sprite_height == 16) as u8) << 5
+            | (c.interrupt as u8) << 7
+    }
+}
+
+impl From<Mask> for u8 {
+    fn from(m: Mask) -> u8 {
+        (m.color == Color::Greyscale) as u8
+            | ((m.background_left == Show::Show) as u8) << 1
+            | ((m.sprites_left == Show::Show) as u8) << 2
+            | ((m.background == Show::Show) as u8) << 3
+            | ((m.sprites == Show::Show) as u8) << 4
+            | ((m.red == Emphasis::On) as u8) << 5
+            | ((m.green == Emphasis::On) as u8) << 6
+            | ((m.blue == Emphasis::On) as u8) << 7
+    }
+}
+
 impl From<Status> for u8 {
     #[inline]
     fn from(s: Status) -> u8 {
@@ -192,3 +247,30 @@ impl From<u8> for Control {
         }
     }
 }
+
+// Shared with the renderer, so it's restored in place.
+impl Snapshot for Rc<Registers> {
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.control.get().into());
+        w.u8(self.mask.get().into());
+        w.u8(self.status.get().into());
+        w.u8(self.oam_addr.get());
+        self.addr.get().save(w);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.control.set(r.u8()?.into());
+        self.mask.set(r.u8()?.into());
+        let status = r.u8()?;
+        self.status.set(Status {
+            overflow: status & (1 << 5) != 0,
+            zero_hit: status & (1 << 6) != 0,
+            vblank: status & (1 << 7) != 0,
+        });
+        self.oam_addr.set(r.u8()?);
+        let mut addr = AddrReg::new();
+        addr.load(r)?;
+        self.addr.set(addr);
+        Ok(())
+    }
+}
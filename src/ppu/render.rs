@@ -1,12 +1,12 @@
 use std::rc::Rc;
 use std::cell::Cell;
 use std::sync::{Mutex, Arc};
-use genawaiter::stack::Co;
-use genawaiter::stack::let_gen_using;
-use genawaiter::GeneratorState;
 
 use image::{ImageBuffer, Bgra};
 use super::{Registers, VAddr, PaletteIdx, regs::Show, palette::TileColor};
+use super::oam::Oam;
+use super::sprite::SpriteRender;
+use crate::state::{Reader, Snapshot, StateError, Writer};
 
 pub struct FrameBuffer {
     buffer: Arc<Mutex<ImageBuffer<Bgra<u8>, Vec<u8>>>>,
@@ -21,17 +21,36 @@ macro_rules! yield_ {
 pub enum VOp {
     Nop,
     Fetch(VAddr),
-    Nmi,
+    // Marks the end of a frame, carrying whether it should also signal NMI.
+    VBlank(bool),
 }
 
 pub struct DrawCommand {
     pub point: (u32, u32),
     pub tile: TileColor,
     pub palette: PaletteIdx,
+    pub layer: Layer,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Layer {
+    Background,
+    Sprite,
+}
+
+// Everything the renderer carries from one dot to the next, kept outside of it so a frame can be
+// saved and picked up again part way through.
+#[derive(Debug, Default)]
+pub struct RenderState {
+    // The line and dot last output.
+    pub position: Cell<(i32, u32)>,
+    background: LiveRender,
+    sprites: SpriteRender,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct LiveRender {
+    tile_id: Cell<u8>,
     attrib: Cell<PaletteIdx>,
     tile_low: Cell<u8>,
     tile_high: Cell<u8>,
@@ -52,6 +71,60 @@ impl LiveRender {
         self.pattern_shift.update(|sh| sh.update(mask));
         self.attrib_shift.update(|sh| sh.update(mask));
     }
+
+    // One of the 8 dots it takes to fetch a tile, given the byte read on the dot before.
+    fn fetch(&self, regs: &Registers, step: u32, byte: u8) -> VOp {
+        let vreg = regs.addr.get();
+        let pattern = || {
+            VAddr::new(
+                ((regs.control.get().bg_table as u16) << 12_u8)
+                    | (u16::from(self.tile_id.get()) << 4_u8)
+                    | u16::from(vreg.get_fine_y().get())
+            )
+            .unwrap()
+        };
+        match step {
+            0 => {
+                self.load_shifters();
+                VOp::Fetch(VAddr::new(0x2000 | (vreg.get_addr().get() & 0xFFF)).unwrap())
+            }
+            1 => {
+                self.tile_id.set(byte);
+                VOp::Nop
+            }
+            2 => VOp::Fetch(
+                VAddr::new(
+                    0x23C0
+                        | (u16::from(vreg.get_nametable().get()) << 10_u8)
+                        | (u16::from((vreg.get_coarse_y().get()) >> 2_u8) << 3_u8)
+                        | (u16::from(vreg.get_coarse_x().get()) >> 2_u8)
+                )
+                .unwrap()
+            ),
+            3 => {
+                let mut attrib = byte;
+                if vreg.get_coarse_y().get() & 0x02 != 0 {
+                    attrib >>= 4;
+                }
+                if vreg.get_coarse_x().get() & 0x02 != 0 {
+                    attrib >>= 2;
+                }
+                self.attrib.set(new_wrapping!(PaletteIdx, attrib));
+                VOp::Nop
+            }
+            4 => VOp::Fetch(pattern()),
+            5 => {
+                self.tile_low.set(byte);
+                VOp::Nop
+            }
+            6 => VOp::Fetch(pattern() + 8),
+            _ => {
+                self.tile_high.set(byte);
+                regs.increment_scrollx();
+                VOp::Nop
+            }
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Default)]
@@ -76,6 +149,18 @@ impl Shift {
     }
 }
 
+impl RenderState {
+    // The dot after the last one output, remembering that line 0 always starts at dot 1.
+    fn next(&self) -> (i32, u32) {
+        match self.position.get() {
+            (-1, 340) => (0, 1),
+            (260, 340) => (-1, 0),
+            (line, 340) => (line + 1, 0),
+            (line, dot) => (line, dot + 1),
+        }
+    }
+}
+
 impl FrameBuffer {
     pub fn new() -> Self {
         FrameBuffer{
@@ -83,29 +168,42 @@ impl FrameBuffer {
         }
     }
 
-    pub async fn clock(regs: Rc<Registers>, co: Co<'_, (VOp, Option<DrawCommand>), u8>) -> ! {
-        let shared = LiveRender::default();
+    // Picks up from the dot after `state.position`, with `byte` the result of the last fetch.
+    pub async fn clock(
+        regs: Rc<Registers>,
+        oam: Rc<Oam>,
+        state: Rc<RenderState>,
+        mut byte: u8,
+        co: genawaiter::rc::Co<(VOp, Option<DrawCommand>), u8>,
+    ) -> ! {
+        let (shared, sprites) = (&state.background, &state.sprites);
 
-        let mut byte = 0_u8;
-
-        for y in (-1_i32..261).cycle().skip(1) {
-            let_gen_using!(scanline, |co| Self::scanline(regs.clone(), &shared, co));
-
-            for x in (if y == 0 { 1_u32 } else { 0 })..341 {
+        let (first, start_x) = state.next();
+        let mut start_x = Some(start_x);
+        for y in (-1_i32..261).cycle().skip((first + 1) as usize) {
+            let start = start_x.take().unwrap_or(if y == 0 { 1_u32 } else { 0 });
+            for x in start..341 {
                 let cmd = match y {
                     -1 ..= 239 => {
                         let mut cmd = VOp::Nop;
                         match x {
-                            1 if y == -1 => regs.set_vblank(false),
-                            2 ..= 257 | 321 ..= 340 => {
+                            1 if y == -1 => {
+                                regs.set_vblank(false);
+                                regs.set_zero_hit(false);
+                                regs.set_overflow(false);
+                            }
+                            2 ..= 257 | 321 ..= 338 => {
                                 if x < 338 {
                                     shared.update(regs.enabled());
                                 }
 
-                                cmd = match scanline.resume_with(byte) {
-                                    GeneratorState::Yielded(cmd) => cmd,
-                                    GeneratorState::Complete(never) => never,
-                                };
+                                // Each tile takes 8 dots, starting again for the next line at 321.
+                                // Nothing is fetched with rendering off, which boards watching
+                                // the bus to count lines rely on.
+                                if regs.enabled() {
+                                    let step = if x <= 257 { x - 2 } else { x - 321 };
+                                    cmd = shared.fetch(&regs, step % 8, byte);
+                                }
                                 if x == 256 {
                                     regs.increment_scrolly();
                                 } else if x == 257 {
@@ -113,108 +211,141 @@ impl FrameBuffer {
                                     regs.transfer_x();
                                 }
                             },
+                            // The last fetch of the line is the same unused nametable byte as the
+                            // one before it, which boards like the MMC5 use to spot the next line.
+                            339 if regs.enabled() => {
+                                let addr = regs.addr.get().get_addr().get();
+                                cmd = VOp::Fetch(VAddr::new(0x2000 | (addr & 0xFFF)).unwrap());
+                            }
                             280 ..= 304 if y == -1 => regs.transfer_y(),
                             _ => (),
                         }
                         if (x, y) == (1, -1) { regs.set_vblank(false); }
+
+                        if regs.enabled() {
+                            match x {
+                                1 ..= 256 if y >= 0 => {
+                                    sprites.evaluate(x, y, &regs, &oam);
+                                }
+                                257 ..= 320 => {
+                                    if let Some(fetch) = sprites.fetch(x - 257, y, &regs) {
+                                        cmd = fetch;
+                                    }
+                                }
+                                _ => (),
+                            }
+                        }
                         cmd
                     },
                     241 if x == 1 => {
                         regs.set_vblank(true);
-                        match regs.interrupt_enabled() {
-                            true => VOp::Nmi,
-                            false => VOp::Nop,
-                        }
+                        VOp::VBlank(regs.interrupt_enabled())
                     },
                     _ => VOp::Nop,
                 };
 
-                let draw = if (1..=256).contains(&x) && (0..240).contains(&y) && regs.mask.get().background == Show::Show {
-                    let addr = regs.addr.get();
+                let draw = if (1..=256).contains(&x) && (0..240).contains(&y) && regs.enabled() {
+                    let mask = regs.mask.get();
+                    let px = x - 1;
+
+                    let (tile, palette) = if mask.background == Show::Show && (px >= 8 || mask.background_left == Show::Show) {
+                        let addr = regs.addr.get();
+
+                        let bit_mux = 0x8000 >> addr.get_fine_x().get();
 
-                    let bit_mux = 0x8000 >> addr.get_fine_x().get();
+                        let pattern = shared.pattern_shift.get();
+                        let p0_pixel = (pattern.low & bit_mux) > 0;
+                        let p1_pixel = (pattern.high & bit_mux) > 0;
 
-                    let pattern = shared.pattern_shift.get();
-                    let p0_pixel = (pattern.low & bit_mux) > 0;
-                    let p1_pixel = (pattern.high & bit_mux) > 0;
+                        let tile = TileColor::new(p0_pixel as u8 | (p1_pixel as u8) << 1).unwrap();
 
-                    let tile = TileColor::new(p0_pixel as u8 | (p1_pixel as u8) << 1).unwrap();
+                        let attrib = shared.attrib_shift.get();
+                        let pal_0 = (attrib.low & bit_mux) > 0;
+                        let pal_1 = (attrib.high & bit_mux) > 0;
 
-                    let attrib = shared.attrib_shift.get();
-                    let pal_0 = (attrib.low & bit_mux) > 0;
-                    let pal_1 = (attrib.high & bit_mux) > 0;
+                        (tile, PaletteIdx::new(pal_0 as u8 | (pal_1 as u8) << 1).unwrap())
+                    } else {
+                        (TileColor::Z, PaletteIdx::Z)
+                    };
 
-                    let palette = PaletteIdx::new(pal_0 as u8 | (pal_1 as u8) << 1).unwrap();
+                    let sprite = if mask.sprites == Show::Show && (px >= 8 || mask.sprites_left == Show::Show) {
+                        sprites.pixel(px)
+                    } else {
+                        None
+                    };
 
-                    Some(DrawCommand{ point: (x - 1, y as u32), tile, palette })
+                    if let Some(s) = &sprite {
+                        // Sprite 0 hit never triggers at x=255.
+                        if s.zero && tile != TileColor::Z && px != 255 {
+                            regs.set_zero_hit(true);
+                        }
+                    }
+
+                    Some(match sprite {
+                        Some(s) if tile == TileColor::Z || !s.behind => {
+                            DrawCommand{ point: (px, y as u32), tile: s.tile, palette: s.palette, layer: Layer::Sprite }
+                        }
+                        _ => DrawCommand{ point: (px, y as u32), tile, palette, layer: Layer::Background },
+                    })
                 } else {
                     None
                 };
 
+                state.position.set((y, x));
                 byte = yield_!((cmd, draw), co);
+                if (257..=320).contains(&x) && regs.enabled() {
+                    sprites.store(x - 257, byte);
+                }
             }
         }
         unreachable!()
     }
+}
 
-    pub async fn scanline(regs: Rc<Registers>, shared: &LiveRender, co: Co<'_, VOp, u8>) -> ! {
-        let vreg = &regs.addr;
-        loop {
-            shared.load_shifters();
-
-            let tile_id = yield_!(
-                VOp::Fetch(VAddr::new(0x2000 | (vreg.get().get_addr().get() & 0xFFF)).unwrap()),
-                co
-            );
-            yield_!(VOp::Nop, co);
-
-            let mut attrib : u8 = yield_!(
-                VOp::Fetch(
-                    VAddr::new(
-                        0x23C0
-                            | (u16::from(vreg.get().get_nametable().get()) << 10_u8)
-                            | (u16::from((vreg.get().get_coarse_x().get()) >> 2_u8) << 3_u8)
-                            | (u16::from(vreg.get().get_coarse_y().get()) >> 2_u8)
-                    )
-                    .unwrap()
-                ),
-                co
-            );
-            if vreg.get().get_coarse_y().get() & 0x02 != 0 {
-                attrib >>= 4;
-            }
-            if vreg.get().get_coarse_x().get() & 0x02 != 0 {
-                attrib >>= 4;
-            }
-            shared.attrib.set(new_wrapping!(PaletteIdx, attrib));
-            yield_!(VOp::Nop, co);
-
-            shared.tile_low.set(yield_!(
-                VOp::Fetch(
-                    VAddr::new(
-                        ((regs.control.get().bg_table as u16) << 12_u8)
-                            | (u16::from(tile_id) << 4_u8)
-                            | u16::from(vreg.get().get_fine_y().get())
-                    )
-                    .unwrap()
-                ),
-                co
-            ));
-            yield_!(VOp::Nop, co);
-
-            shared.tile_high.set(yield_!(
-                VOp::Fetch(
-                    VAddr::new(
-                        ((regs.control.get().bg_table as u16) << 12_u8)
-                            | (u16::from(tile_id) << 4_u8)
-                            | u16::from(vreg.get().get_fine_y().get())
-                    )
-                    .unwrap() + 8
-                ),
-                co
-            ));
-            regs.increment_scrollx();
-            yield_!(VOp::Nop, co);
+impl Snapshot for RenderState {
+    fn save(&self, w: &mut Writer) {
+        let (line, dot) = self.position.get();
+        w.u16(line as u16);
+        w.u16(dot as u16);
+        self.background.save(w);
+        self.sprites.save(w);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        let line = r.u16()? as i16 as i32;
+        let dot = u32::from(r.u16()?);
+        if !(-1..261).contains(&line) || dot > 340 {
+            return Err(StateError::Invalid("PPU position"));
+        }
+        self.position.set((line, dot));
+        self.background.load(r)?;
+        self.sprites.load(r)
+    }
+}
+
+impl Snapshot for LiveRender {
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.tile_id.get());
+        w.u8(self.attrib.get().get());
+        w.u8(self.tile_low.get());
+        w.u8(self.tile_high.get());
+        for shift in [self.attrib_shift.get(), self.pattern_shift.get()].iter() {
+            w.u16(shift.low);
+            w.u16(shift.high);
+        }
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.tile_id.set(r.u8()?);
+        self.attrib.set(PaletteIdx::new(r.u8()?).ok_or(StateError::Invalid("attribute"))?);
+        self.tile_low.set(r.u8()?);
+        self.tile_high.set(r.u8()?);
+        for shift in [&self.attrib_shift, &self.pattern_shift].iter() {
+            shift.set(Shift {
+                low: r.u16()?,
+                high: r.u16()?,
+            });
         }
+        Ok(())
     }
 }
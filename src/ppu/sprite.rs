@@ -0,0 +1,249 @@
+use std::cell::Cell;
+
+use super::oam::{Oam, Sprite};
+use super::palette::{PaletteIdx, TileColor};
+use super::regs::Registers;
+use super::render::VOp;
+use super::VAddr;
+use crate::state::{Reader, Snapshot, StateError, Writer};
+
+#[derive(Debug, Clone, Default)]
+pub struct SpriteRender {
+    secondary: [Cell<Sprite>; 8],
+    eval: Cell<Evaluation>,
+    slots: [Cell<Slot>; 8],
+    count: Cell<u8>,
+    zero: Cell<bool>,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct Evaluation {
+    n: u8,
+    m: u8,
+    found: u8,
+    latch: u8,
+    zero: bool,
+    done: bool,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct Slot {
+    sprite: Sprite,
+    low: u8,
+    high: u8,
+}
+
+pub struct SpritePixel {
+    pub tile: TileColor,
+    pub palette: PaletteIdx,
+    pub behind: bool,
+    pub zero: bool,
+}
+
+impl Evaluation {
+    fn next_sprite(&mut self) {
+        self.m = 0;
+        self.n += 1;
+        if self.n == 64 {
+            self.n = 0;
+            self.done = true;
+        }
+    }
+}
+
+fn in_range(line: i32, y: u8, height: u8) -> bool {
+    (0..i32::from(height)).contains(&(line - i32::from(y)))
+}
+
+impl SpriteRender {
+    // Dots 1-64 clear secondary OAM, 65-256 copy the sprites on the next line into it.
+    pub fn evaluate(&self, dot: u32, line: i32, regs: &Registers, oam: &Oam) {
+        let height = regs.control.get().sprite_height;
+        match dot {
+            1..=64 => {
+                if dot % 2 == 0 {
+                    let idx = (dot / 2 - 1) as u8;
+                    let slot = &self.secondary[usize::from(idx / 4)];
+                    slot.set(slot.get().write_byte(idx, 0xFF));
+                }
+                if dot == 64 {
+                    self.eval.set(Evaluation::default());
+                }
+            }
+            65..=256 => {
+                let mut eval = self.eval.get();
+                if dot % 2 == 1 {
+                    eval.latch = oam.read_byte(eval.n * 4 + eval.m);
+                } else if !eval.done {
+                    if eval.found < 8 {
+                        let slot = &self.secondary[usize::from(eval.found)];
+                        slot.set(slot.get().write_byte(eval.m, eval.latch));
+
+                        if eval.m == 0 && !in_range(line, eval.latch, height) {
+                            eval.next_sprite();
+                        } else {
+                            if eval.m == 0 && eval.n == 0 {
+                                eval.zero = true;
+                            }
+                            eval.m += 1;
+                            if eval.m == 4 {
+                                eval.found += 1;
+                                eval.next_sprite();
+                            }
+                        }
+                    } else if in_range(line, eval.latch, height) {
+                        regs.set_overflow(true);
+                        eval.done = true;
+                    } else {
+                        // Hardware bug: m is incremented along with n, so once secondary
+                        // OAM is full the wrong bytes get treated as Y coordinates.
+                        eval.m = (eval.m + 1) % 4;
+                        eval.n += 1;
+                        if eval.n == 64 {
+                            eval.n = 0;
+                            eval.done = true;
+                        }
+                    }
+                }
+                self.eval.set(eval);
+            }
+            _ => (),
+        }
+    }
+
+    // Dots 257-320 fetch the patterns for the sprites found by evaluation, 8 dots each.
+    pub fn fetch(&self, dot: u32, line: i32, regs: &Registers) -> Option<VOp> {
+        let idx = (dot / 8) as usize;
+        match dot % 8 {
+            0 => {
+                if dot == 0 {
+                    // No evaluation happens on the pre-render line.
+                    let eval = if line < 0 { Evaluation::default() } else { self.eval.get() };
+                    self.count.set(eval.found);
+                    self.zero.set(eval.zero);
+                }
+                let sprite = if idx < usize::from(self.count.get()) {
+                    self.secondary[idx].get()
+                } else {
+                    Sprite::FILLER
+                };
+                self.slots[idx].set(Slot {
+                    sprite,
+                    low: 0,
+                    high: 0,
+                });
+                None
+            }
+            4 => Some(VOp::Fetch(self.pattern_addr(idx, line, regs))),
+            6 => Some(VOp::Fetch(self.pattern_addr(idx, line, regs) + 8)),
+            _ => None,
+        }
+    }
+
+    pub fn store(&self, dot: u32, byte: u8) {
+        let idx = (dot / 8) as usize;
+        let slot = &self.slots[idx];
+        let mut data = slot.get();
+
+        let byte = if idx >= usize::from(self.count.get()) {
+            0
+        } else if data.sprite.flip_x() {
+            byte.reverse_bits()
+        } else {
+            byte
+        };
+
+        match dot % 8 {
+            4 => data.low = byte,
+            6 => data.high = byte,
+            _ => return,
+        }
+        slot.set(data);
+    }
+
+    fn pattern_addr(&self, idx: usize, line: i32, regs: &Registers) -> VAddr {
+        let control = regs.control.get();
+        let sprite = self.slots[idx].get().sprite;
+        let height = u16::from(control.sprite_height);
+
+        let mut row = (line - i32::from(sprite.y)) as u16 % height;
+        if sprite.flip_y() {
+            row = height - 1 - row;
+        }
+
+        let (table, tile) = if height == 16 {
+            (sprite.tile & 1, (sprite.tile & 0xFE) | (row >= 8) as u8)
+        } else {
+            (control.sprite_table as u8, sprite.tile)
+        };
+
+        VAddr::new((u16::from(table) << 12) | (u16::from(tile) << 4) | (row % 8)).unwrap()
+    }
+
+    pub fn pixel(&self, x: u32) -> Option<SpritePixel> {
+        self.slots[..usize::from(self.count.get())]
+            .iter()
+            .map(Cell::get)
+            .enumerate()
+            .find_map(|(idx, slot)| {
+                let dx = x.wrapping_sub(u32::from(slot.sprite.x));
+                if dx >= 8 {
+                    return None;
+                }
+                let bit = 7 - dx;
+                let color = ((slot.low >> bit) & 1) | (((slot.high >> bit) & 1) << 1);
+                if color == 0 {
+                    return None;
+                }
+                Some(SpritePixel {
+                    tile: TileColor::new(color).unwrap(),
+                    palette: slot.sprite.palette(),
+                    behind: slot.sprite.behind(),
+                    zero: idx == 0 && self.zero.get(),
+                })
+            })
+    }
+}
+
+impl Snapshot for SpriteRender {
+    fn save(&self, w: &mut Writer) {
+        self.secondary.iter().for_each(|sprite| sprite.get().save(w));
+        let eval = self.eval.get();
+        w.bytes(&[eval.n, eval.m, eval.found, eval.latch]);
+        w.bool(eval.zero);
+        w.bool(eval.done);
+        for slot in self.slots.iter().map(Cell::get) {
+            slot.sprite.save(w);
+            w.bytes(&[slot.low, slot.high]);
+        }
+        w.u8(self.count.get());
+        w.bool(self.zero.get());
+    }
+
+    // The counts index OAM and the sprite slots, so they're checked rather than trusted.
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        for sprite in self.secondary.iter_mut() {
+            sprite.get_mut().load(r)?;
+        }
+        let mut bytes = [0; 4];
+        r.bytes(&mut bytes)?;
+        let [n, m, found, latch] = bytes;
+        if n >= 64 || m >= 4 || found > 8 {
+            return Err(StateError::Invalid("sprite evaluation"));
+        }
+        let (zero, done) = (r.bool()?, r.bool()?);
+        self.eval.set(Evaluation { n, m, found, latch, zero, done });
+        for slot in self.slots.iter_mut() {
+            let slot = slot.get_mut();
+            slot.sprite.load(r)?;
+            slot.low = r.u8()?;
+            slot.high = r.u8()?;
+        }
+        self.count.set(r.u8()?);
+        if self.count.get() > 8 {
+            return Err(StateError::Invalid("sprite count"));
+        }
+        self.zero.set(r.bool()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt::{self, Display, Formatter};
+
+pub const MAGIC: &[u8; 4] = b"MYNS";
+// Bump whenever the layout of any saved component changes.
+pub const VERSION: u16 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    Magic,
+    Version(u16),
+    Truncated,
+    Mapper,
+    Invalid(&'static str),
+    // Saving was attempted part way through an instruction.
+    Busy,
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            StateError::Magic => write!(f, "Not a save state"),
+            StateError::Version(v) => write!(f, "Unsupported save state version: {}", v),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Mapper => write!(f, "Save state is for a different mapper"),
+            StateError::Invalid(what) => write!(f, "Invalid {} in save state", what),
+            StateError::Busy => write!(f, "Can't save part way through an instruction"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub trait Snapshot {
+    fn save(&self, w: &mut Writer);
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError>;
+}
+
+// Everything is stored little endian, in the order it was written.
+#[derive(Default)]
+pub struct Writer(Vec<u8>);
+
+pub struct Reader<'a>(&'a [u8]);
+
+impl Writer {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn finish(self) -> Vec<u8> { self.0 }
+
+    pub fn u8(&mut self, val: u8) { self.0.push(val); }
+
+    pub fn bool(&mut self, val: bool) { self.u8(val as u8); }
+
+    pub fn u16(&mut self, val: u16) { self.bytes(&val.to_le_bytes()); }
+
+    pub fn u32(&mut self, val: u32) { self.bytes(&val.to_le_bytes()); }
+
+    pub fn u64(&mut self, val: u64) { self.bytes(&val.to_le_bytes()); }
+
+    pub fn bytes(&mut self, val: &[u8]) { self.0.extend_from_slice(val); }
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self { Reader(data) }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        let mut buf = [0];
+        self.bytes(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("flag")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let mut buf = [0; 2];
+        self.bytes(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let mut buf = [0; 4];
+        self.bytes(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let mut buf = [0; 8];
+        self.bytes(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        if self.0.len() < out.len() {
+            return Err(StateError::Truncated);
+        }
+        let (head, rest) = self.0.split_at(out.len());
+        out.copy_from_slice(head);
+        self.0 = rest;
+        Ok(())
+    }
+}
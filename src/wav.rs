@@ -0,0 +1,75 @@
+use std::io::{self, Read, Write};
+
+// Mono 16-bit PCM, which is all the mixer ever produces.
+const CHANNELS: u16 = 1;
+const BITS: u16 = 16;
+
+pub fn to_pcm(sample: f32) -> i16 { (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16 }
+
+pub fn write(mut out: impl Write, rate: u32, samples: &[i16]) -> io::Result<()> {
+    let data_len = (samples.len() * 2) as u32;
+    let block_align = CHANNELS * BITS / 8;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16_u32.to_le_bytes())?;
+    out.write_all(&1_u16.to_le_bytes())?;
+    out.write_all(&CHANNELS.to_le_bytes())?;
+    out.write_all(&rate.to_le_bytes())?;
+    out.write_all(&(rate * u32::from(block_align)).to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&BITS.to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        out.write_all(&sample.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// Reads back the sample rate and samples of a file in the format written above.
+pub fn read(mut input: impl Read) -> io::Result<(u32, Vec<i16>)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(invalid("not a WAVE file"));
+    }
+
+    let mut rate = None;
+    let mut rest = &bytes[12..];
+    while rest.len() >= 8 {
+        let (header, body) = rest.split_at(8);
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if body.len() < len {
+            return Err(invalid("truncated chunk"));
+        }
+        let chunk = &body[..len];
+
+        match &header[0..4] {
+            b"fmt " if len >= 16 => {
+                let format = u16::from_le_bytes([chunk[0], chunk[1]]);
+                let channels = u16::from_le_bytes([chunk[2], chunk[3]]);
+                let bits = u16::from_le_bytes([chunk[14], chunk[15]]);
+                if (format, channels, bits) != (1, CHANNELS, BITS) {
+                    return Err(invalid("only mono 16-bit PCM is supported"));
+                }
+                rate = Some(u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]));
+            }
+            b"data" => {
+                let rate = rate.ok_or_else(|| invalid("data before fmt chunk"))?;
+                let samples = chunk.chunks_exact(2).map(|s| i16::from_le_bytes([s[0], s[1]]));
+                return Ok((rate, samples.collect()));
+            }
+            _ => (),
+        }
+        // Chunks are padded to an even length.
+        rest = &body[(len + (len & 1)).min(body.len())..];
+    }
+    Err(invalid("missing data chunk"))
+}
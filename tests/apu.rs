@@ -0,0 +1,211 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use mynes::{wav, FilterPreset, Nes, Region, Rom};
+
+// Largest per-sample difference from the reference that still passes.
+const TOLERANCE: i16 = 64;
+
+// References live in tests/audio/<name>.wav. On a mismatch the actual output is written next to
+// it as <name>.actual.wav, which can be listened to and renamed to accept it.
+fn test_rom(name: &str, rom: &[u8], frames: usize) {
+    let rom = Rom::parse(rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    let actual = nes.record_audio(frames).unwrap();
+    let rate = nes.sample_rate();
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/audio");
+    let expected = dir.join(format!("{}.wav", name));
+    let output = dir.join(format!("{}.actual.wav", name));
+
+    let matches = match File::open(&expected).and_then(|f| wav::read(BufReader::new(f))) {
+        Ok((expected_rate, samples)) => {
+            expected_rate == rate
+                && samples.len() == actual.len()
+                && samples.iter().zip(&actual).all(|(&a, &b)| (i32::from(a) - i32::from(b)).abs() <= TOLERANCE.into())
+        }
+        Err(_) => false,
+    };
+    if !matches {
+        wav::write(File::create(&output).unwrap(), rate, &actual).unwrap();
+        panic!("{} does not match {}", output.display(), expected.display());
+    }
+}
+
+macro_rules! test_file {
+    ($name:ident($path:expr, $frames:expr)) => {
+        #[test]
+        fn $name() { test_rom(stringify!($name), include_bytes!(concat!("roms/", $path, ".nes")), $frames) }
+    };
+
+    ([i]$name:ident($path:expr, $frames:expr)) => {
+        #[test]
+        #[ignore]
+        fn $name() { test_rom(stringify!($name), include_bytes!(concat!("roms/", $path, ".nes")), $frames) }
+    };
+}
+
+// None of these have recorded references yet.
+test_file!([i]square("nes-test-roms/apu_mixer/square", 600));
+test_file!([i]triangle("nes-test-roms/apu_mixer/triangle", 600));
+test_file!([i]noise("nes-test-roms/apu_mixer/noise", 600));
+test_file!([i]dmc("nes-test-roms/apu_mixer/dmc", 600));
+
+// What the harness writes on a mismatch reads back as it was recorded.
+#[test]
+fn wav_round_trip() {
+    let samples = [0.0, 0.5, -0.5, 1.0, -1.0, 2.0, -2.0];
+    let pcm: Vec<i16> = samples.iter().map(|&s| wav::to_pcm(s)).collect();
+    assert_eq!(pcm, [0, 16383, -16383, i16::MAX, -i16::MAX, i16::MAX, -i16::MAX]);
+
+    let mut file = Vec::new();
+    wav::write(&mut file, 44_100, &pcm).unwrap();
+    assert_eq!(file.len(), 44 + 2 * pcm.len());
+    assert_eq!(wav::read(&file[..]).unwrap(), (44_100, pcm));
+
+    assert!(wav::read(&file[..40]).is_err());
+    assert!(wav::read(&b"RIFF\0\0\0\0WAVE"[..]).is_err());
+}
+
+// An NROM image running the given code from $8000, then spinning.
+fn program(code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 16 + 0x4000 + 0x2000];
+    rom[..16].copy_from_slice(&[b'N', b'E', b'S', 0x1a, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let prg = &mut rom[16..16 + 0x4000];
+    prg[..code.len()].copy_from_slice(code);
+    // NOP; JMP back to it
+    let end = 0x8000 + code.len() as u16;
+    prg[code.len()..code.len() + 4].copy_from_slice(&[0xea, 0x4c, end as u8, (end >> 8) as u8]);
+    prg[0x3ffc..0x3ffe].copy_from_slice(&[0x00, 0x80]);
+    rom
+}
+
+// The samples from the given number of frames after the first, which sets up the channels.
+fn samples(rom: &[u8], preset: FilterPreset, rate: u32, frames: usize) -> Vec<f32> {
+    let rom = Rom::parse(rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    nes.set_audio_filter(preset);
+    nes.set_sample_rate(rate);
+    nes.run_frame().unwrap();
+    nes.audio_samples().for_each(drop);
+
+    let mut samples = Vec::new();
+    for _ in 0..frames {
+        nes.run_frame().unwrap();
+        samples.extend(nes.audio_samples());
+    }
+    samples
+}
+
+fn assert_level(actual: f32, expected: f32) {
+    assert!((actual - expected).abs() < 1e-4, "{} isn't {}", actual, expected);
+}
+
+// LDA #64; STA $4011
+const DMC_64: &[u8] = &[0xa9, 0x40, 0x8d, 0x11, 0x40];
+
+// Pulse 1 at a constant volume of 15, with a 50% duty cycle and a period of $FF.
+const PULSE: &[u8] = &[
+    0xa9, 0x01, 0x8d, 0x15, 0x40, // LDA #$01; STA $4015
+    0xa9, 0xbf, 0x8d, 0x00, 0x40, // LDA #$BF; STA $4000
+    0xa9, 0xff, 0x8d, 0x02, 0x40, // LDA #$FF; STA $4002
+    0xa9, 0x00, 0x8d, 0x03, 0x40, // LDA #$00; STA $4003
+];
+
+#[test]
+fn pulse() { test_rom("pulse", &program(PULSE), 10) }
+
+// The levels from the mixer formulas on https://wiki.nesdev.com/w/index.php/APU_Mixer, with the
+// triangle left at the top of its sequence, 15, as it is at power on.
+#[test]
+fn mixer_levels() {
+    let silent = samples(&program(&[]), FilterPreset::Raw, 44_100, 5);
+    silent.iter().for_each(|&s| assert_level(s, 0.255_477));
+
+    let dmc = samples(&program(DMC_64), FilterPreset::Raw, 44_100, 5);
+    dmc.iter().for_each(|&s| assert_level(s, 0.506_402));
+
+    let pulse = samples(&program(PULSE), FilterPreset::Raw, 44_100, 5);
+    let max = pulse.iter().cloned().fold(f32::MIN, f32::max);
+    let min = pulse.iter().cloned().fold(f32::MAX, f32::min);
+    assert_level(max, 0.148_816 + 0.255_477);
+    assert_level(min, 0.255_477);
+}
+
+// The resampler keeps up with the CPU, at 29780.5 cycles a frame.
+#[test]
+fn sample_counts() {
+    for &(rate, expected) in &[(44_100, 44_027), (48_000, 47_921)] {
+        let count = samples(&program(&[]), FilterPreset::Raw, rate, 60).len();
+        assert!((expected - 2..=expected + 2).contains(&count), "{} samples at {}Hz", count, rate);
+    }
+
+    // PAL games still run with NTSC timing, so their audio has to keep NTSC speed too.
+    let mut pal = program(&[]);
+    pal[9] = 0x01;
+    assert_eq!(Rom::parse(&pal).unwrap().region(), Region::PAL);
+    let count = samples(&pal, FilterPreset::Raw, 44_100, 60).len();
+    assert!((44_025..=44_029).contains(&count), "{} samples for PAL", count);
+}
+
+// The high-pass stages of both consoles take out the DC offset, which the raw output keeps.
+#[test]
+fn filter_presets() {
+    let raw = samples(&program(DMC_64), FilterPreset::Raw, 44_100, 30);
+    assert_level(*raw.last().unwrap(), 0.506_402);
+    for &preset in &[FilterPreset::Nes, FilterPreset::Famicom] {
+        let filtered = samples(&program(DMC_64), preset, 44_100, 30);
+        assert!(filtered.last().unwrap().abs() < 1e-3, "{:?}", preset);
+    }
+}
+
+// Only sets up the APU on the first boot, counting boots in battery backed RAM so the count
+// survives a power cycle. Every channel gets a long length, then `frame` is written to $4017.
+fn first_boot(frame: u8) -> Vec<u8> {
+    let mut rom = program(&[
+        0xee, 0x00, 0x60, 0xad, 0x00, 0x60, // INC $6000; LDA $6000
+        0xc9, 0x01, 0xd0, 0x18, // CMP #1; BNE past the rest
+        0xa9, 0x0f, 0x8d, 0x15, 0x40, // LDA #$0F; STA $4015
+        0xa9, 0x08, 0x8d, 0x03, 0x40, 0x8d, 0x07, 0x40, // LDA #$08; STA $4003; STA $4007
+        0x8d, 0x0b, 0x40, 0x8d, 0x0f, 0x40, // STA $400B; STA $400F
+        0xa9, frame, 0x8d, 0x17, 0x40, // LDA #frame; STA $4017
+    ]);
+    rom[6] = 0x02;
+    rom
+}
+
+// Runs two frames and reads $4015, which also acknowledges the frame IRQ.
+fn status_after_frames(nes: &mut Nes) -> u8 {
+    nes.run_frame().unwrap();
+    nes.run_frame().unwrap();
+    nes.get_mem(0x4015)
+}
+
+// Resetting silences every channel and clears the frame IRQ, but $4017 is written again with its
+// last value. Powering on writes $00 instead.
+#[test]
+fn reset() {
+    let rom = first_boot(0x00);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    assert_eq!(status_after_frames(&mut nes), 0x4f);
+    // Raises the frame IRQ again, without reading $4015.
+    nes.run_frame().unwrap();
+    nes.run_frame().unwrap();
+    nes.reset();
+    assert_eq!(nes.get_mem(0x4015), 0x00);
+    assert_eq!(status_after_frames(&mut nes), 0x40);
+
+    let rom = first_boot(0x80);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    assert_eq!(status_after_frames(&mut nes), 0x0f);
+    nes.reset();
+    assert_eq!(nes.get_mem(0x4015), 0x00);
+    assert_eq!(status_after_frames(&mut nes), 0x00);
+    nes.power_on();
+    assert_eq!(nes.get_mem(0x4015), 0x00);
+    assert_eq!(status_after_frames(&mut nes), 0x40);
+    assert_eq!(nes.sram().unwrap()[0], 3);
+}
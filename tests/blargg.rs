@@ -5,14 +5,17 @@ use mynes::Rom;
 
 fn test_rom(rom: &[u8]) -> io::Result<()> {
     let rom = Rom::parse(&rom[..]).unwrap();
-    let mut nes = Nes::new(&rom);
-    let e = nes.run();
+    let mut nes = Nes::new(&rom).unwrap();
+    let mut e = nes.run();
+
+    // $81 asks for the reset button to be pressed, once the test has finished waiting.
+    while e.is_ok() && nes.get_mem(0x6000) == 0x81 {
+        nes.reset();
+        e = nes.run();
+    }
 
     //eprintln!("{:#?}", nes.cpu);
     let status = nes.get_mem(0x6000);
-    if status == 0x81 {
-        todo!("Reset");
-    }
     eprintln!("({:02x})", status);
 
     let mut msg = String::new();
@@ -91,18 +94,32 @@ test_file!(branch_timing(
 ));
 
 test_file!(apu_test("nes-test-roms/apu_test/apu_test"));
-test_file!([i]len_ctr("nes-test-roms/apu_test/rom_singles/1-len_ctr"));
-test_file!([i]len_table("nes-test-roms/apu_test/rom_singles/2-len_table"));
-test_file!([i]irq_flag("nes-test-roms/apu_test/rom_singles/3-irq_flag"));
+test_file!(len_ctr("nes-test-roms/apu_test/rom_singles/1-len_ctr"));
+test_file!(len_table("nes-test-roms/apu_test/rom_singles/2-len_table"));
+test_file!(irq_flag("nes-test-roms/apu_test/rom_singles/3-irq_flag"));
 test_file!([i]jitter("nes-test-roms/apu_test/rom_singles/4-jitter"));
 test_file!([i]len_timing("nes-test-roms/apu_test/rom_singles/5-len_timing"));
-test_file!([i]irq_flag_timing("nes-test-roms/apu_test/rom_singles/6-irq_flag_timing"));
-test_file!([i]dmc_basics("nes-test-roms/apu_test/rom_singles/7-dmc_basics"));
-test_file!([i]dmc_rates("nes-test-roms/apu_test/rom_singles/8-dmc_rates"));
+test_file!(irq_flag_timing("nes-test-roms/apu_test/rom_singles/6-irq_flag_timing"));
+test_file!(dmc_basics("nes-test-roms/apu_test/rom_singles/7-dmc_basics"));
+test_file!(dmc_rates("nes-test-roms/apu_test/rom_singles/8-dmc_rates"));
 
+// Not yet run against the real ROMs. The reset test in tests/apu.rs checks $4015, the frame IRQ
+// and the $4017 mode without them.
 test_file!([i]apu_4015_cleared("nes-test-roms/apu_reset/4015_cleared"));
 test_file!([i]apu_4017_timing("nes-test-roms/apu_reset/4017_timing"));
 test_file!([i]apu_4017_written("nes-test-roms/apu_reset/4017_written"));
 test_file!([i]apu_irq_flag_cleared("nes-test-roms/apu_reset/irq_flag_cleared"));
 test_file!([i]apu_len_ctrs_enabled("nes-test-roms/apu_reset/len_ctrs_enabled"));
 test_file!([i]apu_works_immediately("nes-test-roms/apu_reset/works_immediately"));
+
+test_file!([i]ppu_vbl_nmi("nes-test-roms/ppu_vbl_nmi/ppu_vbl_nmi"));
+test_file!([i]vbl_basics("nes-test-roms/ppu_vbl_nmi/rom_singles/01-vbl_basics"));
+test_file!([i]vbl_set_time("nes-test-roms/ppu_vbl_nmi/rom_singles/02-vbl_set_time"));
+test_file!([i]vbl_clear_time("nes-test-roms/ppu_vbl_nmi/rom_singles/03-vbl_clear_time"));
+test_file!([i]nmi_control("nes-test-roms/ppu_vbl_nmi/rom_singles/04-nmi_control"));
+test_file!([i]nmi_timing("nes-test-roms/ppu_vbl_nmi/rom_singles/05-nmi_timing"));
+test_file!([i]suppression("nes-test-roms/ppu_vbl_nmi/rom_singles/06-suppression"));
+test_file!([i]nmi_on_timing("nes-test-roms/ppu_vbl_nmi/rom_singles/07-nmi_on_timing"));
+test_file!([i]nmi_off_timing("nes-test-roms/ppu_vbl_nmi/rom_singles/08-nmi_off_timing"));
+test_file!([i]even_odd_frames("nes-test-roms/ppu_vbl_nmi/rom_singles/09-even_odd_frames"));
+test_file!([i]even_odd_timing("nes-test-roms/ppu_vbl_nmi/rom_singles/10-even_odd_timing"));
@@ -0,0 +1,755 @@
+use mynes::ppu::{Nametable, VAddr};
+use mynes::state::{Reader, Snapshot, StateError, Writer};
+use mynes::{
+    Cartridge,
+    Console,
+    Mapper,
+    Nes,
+    Region,
+    Registry,
+    Rom,
+    RomError,
+    UnknownMapper,
+    Version,
+};
+
+// Builds an image with the given header and 16KiB of PRG ROM running the given code from $8000,
+// followed by as much CHR ROM as the header asks for.
+fn image(header: [u8; 16], code: &[u8]) -> Vec<u8> {
+    let chr = usize::from(header[5]) * 0x2000;
+    let mut rom = vec![0; 16 + 0x4000 + chr];
+    rom[..16].copy_from_slice(&header);
+    let prg = &mut rom[16..16 + 0x4000];
+    prg[..code.len()].copy_from_slice(code);
+    // Reset vector
+    prg[0x3ffc..0x3ffe].copy_from_slice(&[0x00, 0x80]);
+    rom
+}
+
+// An iNES NROM image with 8KiB of CHR ROM and the given flags 6 bits.
+fn rom(flags6: u8, code: &[u8]) -> Vec<u8> {
+    image([b'N', b'E', b'S', 0x1a, 1, 1, flags6, 0, 0, 0, 0, 0, 0, 0, 0, 0], code)
+}
+
+// LDA $6000; STA $6001; LDA #$42; STA $6000; JMP *
+const COPY_SRAM: &[u8] = &[
+    0xad, 0x00, 0x60, 0x8d, 0x01, 0x60, 0xa9, 0x42, 0x8d, 0x00, 0x60, 0x4c, 0x0b, 0x80,
+];
+
+#[test]
+fn battery_sram() {
+    let rom = rom(0x02, COPY_SRAM);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    nes.load_sram(&[0x17]);
+    nes.run().unwrap();
+
+    let sram = nes.sram().unwrap();
+    assert_eq!(sram.len(), 0x2000);
+    assert_eq!(sram[..2], [0x42, 0x17]);
+}
+
+#[test]
+fn no_battery() {
+    let rom = rom(0x00, COPY_SRAM);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    nes.run().unwrap();
+
+    assert!(nes.sram().is_none());
+}
+
+// INC $6000; JMP *
+const COUNT_RESETS: &[u8] = &[0xee, 0x00, 0x60, 0x4c, 0x03, 0x80];
+
+#[test]
+fn reset() {
+    let rom = rom(0x02, COUNT_RESETS);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    nes.run().unwrap();
+    assert_eq!(nes.cpu().stack.0, 0xfd);
+
+    nes.reset();
+    nes.run().unwrap();
+    assert_eq!(nes.sram().unwrap()[0], 2);
+    assert_eq!(nes.cpu().stack.0, 0xfa);
+    assert!(nes.cpu().status.i);
+
+    // Battery backed RAM survives a power cycle.
+    nes.power_on();
+    nes.run().unwrap();
+    assert_eq!(nes.sram().unwrap()[0], 3);
+    assert_eq!(nes.cpu().stack.0, 0xfd);
+}
+
+// LDA #0; STA $2001; STA $2006 * 2; LDA #$5A; STA $2007; LDA #0; STA $2006 * 2; LDA $2007 * 2;
+// STA $6000; JMP *
+const CHR_RAM: &[u8] = &[
+    0xa9, 0x00, 0x8d, 0x01, 0x20, 0x8d, 0x06, 0x20, 0x8d, 0x06, 0x20, 0xa9, 0x5a, 0x8d, 0x07, 0x20,
+    0xa9, 0x00, 0x8d, 0x06, 0x20, 0x8d, 0x06, 0x20, 0xad, 0x07, 0x20, 0xad, 0x07, 0x20, 0x8d, 0x00,
+    0x60, 0x4c, 0x21, 0x80,
+];
+
+#[test]
+fn nes2_header() {
+    #[rustfmt::skip]
+    let header = [
+        b'N', b'E', b'S', 0x1a,
+        // 2^14 * 1 bytes of PRG ROM, no CHR ROM
+        0x38, 0x00,
+        0x02, 0x08,
+        // Submapper 3
+        0x30,
+        0x0f,
+        // 8KiB of PRG NVRAM and CHR RAM
+        0x70, 0x07,
+        // Dendy
+        0x03,
+        0x00, 0x00,
+        // Zapper
+        0x08,
+    ];
+    let rom = image(header, CHR_RAM);
+    let rom = Rom::parse(&rom).unwrap();
+    assert_eq!(rom.submapper(), 3);
+    assert_eq!(rom.region(), Region::Dendy);
+    assert_eq!(rom.console(), Console::Nes);
+    assert_eq!(rom.prg_ram_size(), 0);
+    assert_eq!(rom.prg_nvram_size(), 0x2000);
+    assert_eq!(rom.chr_ram_size(), 0x2000);
+    assert_eq!(rom.expansion_device(), 0x08);
+
+    let mut nes = Nes::new(&rom).unwrap();
+    assert_eq!(nes.region(), Region::Dendy);
+    nes.run().unwrap();
+    assert_eq!(nes.sram().unwrap()[0], 0x5a);
+}
+
+#[test]
+fn malformed_headers() {
+    let valid = rom(0x00, &[]);
+    let header = |bytes: &[(usize, u8)]| {
+        let mut rom = valid.clone();
+        bytes.iter().for_each(|&(i, b)| rom[i] = b);
+        rom
+    };
+
+    let cases: &[(&[u8], RomError)] = &[
+        (b"", RomError::Magic),
+        (b"NES", RomError::Magic),
+        (b"NES\x1b\x01\x01\x00\x00", RomError::Magic),
+        (&valid[..10], RomError::TruncatedHeader),
+        (&header(&[(6, 0x04)])[..16 + 0x100], RomError::TruncatedTrainer),
+        (&valid[..16 + 0x1000], RomError::TruncatedPrg),
+        (&valid[..16 + 0x4000 + 1], RomError::TruncatedChr),
+        (&header(&[(4, 2)]), RomError::TruncatedPrg),
+        (&header(&[(5, 2)]), RomError::TruncatedChr),
+        // A 2^63 byte PRG ROM
+        (&header(&[(4, 0xFC), (7, 0x08), (9, 0x0F)]), RomError::Unsupported(Version::Nes2_0)),
+        (&header(&[(4, 0)]), RomError::PrgSize),
+        // 8KiB of PRG ROM, and 1KiB of CHR ROM
+        (&header(&[(4, 0x34), (7, 0x08), (9, 0x0F)]), RomError::PrgSize),
+        (&header(&[(5, 0x28), (7, 0x08), (9, 0xF0)]), RomError::ChrSize),
+    ];
+    for (i, (rom, err)) in cases.iter().enumerate() {
+        assert_eq!(Rom::parse(rom).err(), Some(*err), "case {}", i);
+    }
+}
+
+#[test]
+fn unknown_mapper() {
+    let mut mapper_15 = rom(0xF0, &[]);
+    let mut mapper_256 = rom(0x00, &[]);
+    mapper_256[7..9].copy_from_slice(&[0x08, 0x01]);
+
+    for (rom, id) in [(&mapper_15, 15), (&mapper_256, 0x100)].iter() {
+        let rom = Rom::parse(rom).unwrap();
+        assert_eq!(Nes::new(&rom).err(), Some(RomError::Mapper(UnknownMapper(*id))));
+    }
+
+    // Submappers without their own constructor fall back to the mapper's.
+    mapper_15[7..9].copy_from_slice(&[0x08, 0x50]);
+    let rom = Rom::parse(&mapper_15).unwrap();
+    let mut registry = Registry::new();
+    registry.register(15, None, counter);
+    assert!(Nes::with_registry(&rom, &registry).is_ok());
+}
+
+// A board that raises IRQ after a thousand CPU cycles, until $6000 is written.
+struct Counter<'a> {
+    prg: &'a [u8],
+    cycles: u64,
+    val: u8,
+}
+
+fn counter<'a>(rom: &Rom<'a>) -> Box<dyn Mapper + 'a> {
+    Box::new(Counter {
+        prg: rom.prg,
+        cycles: 0,
+        val: 0,
+    })
+}
+
+impl<'a> Mapper for Counter<'a> {
+    fn get(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000 => self.val,
+            0x8000..=0xffff => self.prg[usize::from(addr - 0x8000) % self.prg.len()],
+            _ => 0,
+        }
+    }
+
+    fn set(&mut self, addr: u16, val: u8) {
+        if addr == 0x6000 {
+            self.val = val;
+        }
+    }
+
+    fn get_ppu(&self, _addr: VAddr) -> u8 { 0 }
+
+    fn set_ppu(&mut self, _addr: VAddr, _val: u8) {}
+
+    fn mirror<'nt>(&'nt self, vram: &'nt [Nametable; 2]) -> [&'nt Nametable; 4] {
+        [&vram[0], &vram[0], &vram[1], &vram[1]]
+    }
+
+    fn irq(&self) -> bool { self.cycles > 1000 && self.val == 0 }
+
+    fn clock(&mut self) { self.cycles += 1; }
+}
+
+impl<'a> Snapshot for Counter<'a> {
+    fn save(&self, w: &mut Writer) {
+        w.u64(self.cycles);
+        w.u8(self.val);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<(), StateError> {
+        self.cycles = r.u64()?;
+        self.val = r.u8()?;
+        Ok(())
+    }
+}
+
+// CLI; NOP; JMP $8001; IRQ: LDA #$42; STA $6000; JMP *
+const WAIT_IRQ: &[u8] = &[
+    0x58, 0xea, 0x4c, 0x01, 0x80, 0xa9, 0x42, 0x8d, 0x00, 0x60, 0x4c, 0x0a, 0x80,
+];
+
+#[test]
+fn custom_mapper() {
+    let mut rom = rom(0xF0, WAIT_IRQ);
+    // IRQ vector
+    rom[16 + 0x3ffe..16 + 0x4000].copy_from_slice(&[0x05, 0x80]);
+    let rom = Rom::parse(&rom).unwrap();
+
+    let mut registry = Registry::new();
+    registry.register(15, None, counter);
+    let mut nes = Nes::with_registry(&rom, &registry).unwrap();
+    nes.run().unwrap();
+    assert_eq!(nes.get_mem(0x6000), 0x42);
+    assert!(nes.cpu().status.i);
+
+    // States remember which board they came from.
+    let state = nes.save_state().unwrap();
+    let nrom = self::rom(0x00, WAIT_IRQ);
+    let nrom = Rom::parse(&nrom).unwrap();
+    let mut other = Nes::new(&nrom).unwrap();
+    assert_eq!(other.load_state(&state).err(), Some(StateError::Mapper));
+}
+
+// A NES 2.0 image with the given numbers of 16KiB PRG and 8KiB CHR banks, each starting with its
+// own number. PRG is otherwise $FF, apart from the same `code` at $x010 in every bank, with the
+// reset vector pointing at the copy in $C000.
+fn banked(mapper: u8, submapper: u8, prg: u8, chr: u8, code: &[u8]) -> Vec<u8> {
+    #[rustfmt::skip]
+    let header = [
+        b'N', b'E', b'S', 0x1a, prg, chr, mapper << 4, mapper & 0xF0 | 0x08, submapper << 4,
+        0, 0, 0x07, 0, 0, 0, 0,
+    ];
+    let mut rom = header.to_vec();
+    for i in 0..prg {
+        let mut bank = vec![0xff; 0x4000];
+        bank[0] = i;
+        bank[0x10..0x10 + code.len()].copy_from_slice(code);
+        bank[0x3ffc..0x3ffe].copy_from_slice(&[0x10, 0xc0]);
+        rom.extend(bank);
+    }
+    for i in 0..chr {
+        let mut bank = vec![0; 0x2000];
+        bank[0] = i;
+        rom.extend(bank);
+    }
+    rom
+}
+
+// Writes the latch with the given value at $FFF0.
+fn write_latch(val: u8) -> [u8; 8] { [0xa9, val, 0x8d, 0xf0, 0xff, 0x4c, 0x15, 0xc0] }
+
+fn read_ppu(nes: &mut Nes, addr: u16) -> u8 {
+    nes.bus.ppu.get_ppu(VAddr::new(addr).unwrap(), &mut nes.bus.cartridge)
+}
+
+fn write_ppu(nes: &mut Nes, addr: u16, val: u8) {
+    nes.bus.ppu.set_ppu(VAddr::new(addr).unwrap(), val, &mut nes.bus.cartridge)
+}
+
+#[test]
+fn discrete_mappers() {
+    // Mapper, PRG and CHR banks, latch, then the banks at $8000, $C000 and PPU $0000.
+    let cases = [
+        (2, 4, 0, 0x02, [2, 3, 0]),
+        (2, 4, 0, 0x05, [1, 3, 0]),
+        (3, 2, 4, 0x02, [0, 1, 2]),
+        (7, 4, 0, 0x01, [2, 3, 0]),
+        (66, 4, 4, 0x13, [2, 3, 3]),
+    ];
+    for &(mapper, prg, chr, latch, banks) in cases.iter() {
+        let rom = banked(mapper, 0, prg, chr, &write_latch(latch));
+        let rom = Rom::parse(&rom).unwrap();
+        let mut nes = Nes::new(&rom).unwrap();
+        nes.run().unwrap();
+
+        let found = [nes.get_mem(0x8000), nes.get_mem(0xc000), read_ppu(&mut nes, 0x0000)];
+        assert_eq!(found, banks, "mapper {} latch {:02x}", mapper, latch);
+    }
+}
+
+#[test]
+fn bus_conflicts() {
+    // Submapper 2 has conflicts, except GxROM which always does.
+    let cases = [(2, 0, 0x03), (2, 2, 0x01), (3, 2, 0x01), (7, 2, 0x01), (66, 0, 0x01)];
+    for &(mapper, submapper, bank) in cases.iter() {
+        let mut rom = banked(mapper, submapper, 4, 4, &write_latch(0x03));
+        // $FFF0, where the latch is written, in whichever bank is there.
+        for bank in 0..4 {
+            rom[16 + bank * 0x4000 + 0x3ff0] = 0x01;
+        }
+        let rom = Rom::parse(&rom).unwrap();
+        let mut nes = Nes::new(&rom).unwrap();
+        nes.run().unwrap();
+
+        let found = match mapper {
+            2 => nes.get_mem(0x8000),
+            7 => nes.get_mem(0x8000) / 2,
+            _ => read_ppu(&mut nes, 0x0000),
+        };
+        assert_eq!(found, bank, "mapper {} submapper {}", mapper, submapper);
+    }
+}
+
+#[test]
+fn single_screen() {
+    // Switches to the upper nametable.
+    let rom = banked(7, 0, 4, 0, &write_latch(0x10));
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    write_ppu(&mut nes, 0x2000, 0x11);
+    assert_eq!(read_ppu(&mut nes, 0x2c00), 0x11);
+
+    nes.run().unwrap();
+    assert_eq!(read_ppu(&mut nes, 0x2000), 0x00);
+    write_ppu(&mut nes, 0x2400, 0x22);
+    assert_eq!(read_ppu(&mut nes, 0x2800), 0x22);
+    assert_eq!(nes.bus.ppu.vram[1].read(0), 0x22);
+}
+
+#[test]
+fn four_screen() {
+    let rom = rom(0x08, &[]);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    for (i, &addr) in [0x2000, 0x2400, 0x2800, 0x2c00].iter().enumerate() {
+        write_ppu(&mut nes, addr, 0x11 * (i as u8 + 1));
+    }
+
+    // The board's own RAM covers the last two, leaving the console's for the first two.
+    let tables: Vec<_> = [0x2000, 0x2400, 0x2800, 0x2c00, 0x3000, 0x3c00]
+        .iter()
+        .map(|&addr| read_ppu(&mut nes, addr))
+        .collect();
+    assert_eq!(tables, [0x11, 0x22, 0x33, 0x44, 0x11, 0x44]);
+    assert_eq!([nes.bus.ppu.vram[0].read(0), nes.bus.ppu.vram[1].read(0)], [0x11, 0x22]);
+}
+
+#[test]
+fn mmc1_state() {
+    let rom = banked(1, 0, 8, 0, &[]);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    // Bank 12 of 8 is selected through the serial port, and reads as bank 4.
+    for bit in 0..5 {
+        nes.bus.cartridge.set(0xe000, 12 >> bit & 1);
+    }
+    assert_eq!(nes.get_mem(0x8000), 4);
+
+    let state = nes.save_state().unwrap();
+    let mut loaded = Nes::new(&rom).unwrap();
+    loaded.load_state(&state).unwrap();
+    assert_eq!(loaded.get_mem(0x8000), 4);
+    assert_eq!(loaded.save_state().unwrap(), state);
+}
+
+// MMC1 boards come with anything from no PRG RAM to 32KiB, and CHR RAM in place of CHR ROM.
+#[test]
+fn mmc1_ram() {
+    for &(prg_ram, size) in &[(0x70, 0x2000), (0x80, 0x4000), (0x00, 0)] {
+        #[rustfmt::skip]
+        let header = [
+            b'N', b'E', b'S', 0x1a, 1, 0, 0x12, 0x08, 0, 0, prg_ram, 0x07, 0, 0, 0, 0,
+        ];
+        let rom = image(header, CHR_RAM);
+        let rom = Rom::parse(&rom).unwrap();
+        let mut nes = Nes::new(&rom).unwrap();
+        nes.run().unwrap();
+
+        let sram = nes.sram().unwrap();
+        assert_eq!(sram.len(), size);
+        if size > 0 {
+            assert_eq!(sram[0], 0x5a);
+        }
+    }
+}
+
+#[test]
+fn mmc1_corrupt_state() {
+    let rom = banked(1, 0, 2, 0, &[]);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut state = Nes::new(&rom).unwrap().save_state().unwrap();
+    // The number of 4KiB CHR RAM banks, straight after the mapper number.
+    state[9..13].copy_from_slice(&u32::MAX.to_le_bytes());
+    let err = Nes::new(&rom).unwrap().load_state(&state);
+    assert_eq!(err, Err(StateError::Invalid("CHR RAM size")));
+}
+
+// Like `banked`, but numbering every 8KiB of PRG, and filling every 1KiB of CHR with its number.
+fn paged(mapper: u8, prg: u8, chr: u8) -> Vec<u8> {
+    let mut rom = banked(mapper, 0, prg, chr, &[]);
+    let chr_start = 16 + usize::from(prg) * 0x4000;
+    for i in 0..usize::from(prg) * 2 {
+        rom[16 + i * 0x2000] = i as u8;
+    }
+    for (i, page) in rom[chr_start..].chunks_mut(0x400).enumerate() {
+        page.iter_mut().for_each(|b| *b = i as u8);
+    }
+    rom
+}
+
+// The pages at $8000, $A000, $C000 and $E000.
+fn prg_pages(cart: &Cartridge) -> Vec<u8> {
+    [0x8000, 0xa000, 0xc000, 0xe000].iter().map(|&a| cart.get(a)).collect()
+}
+
+// The pages at every 1KiB of the pattern tables.
+fn chr_pages(cart: &Cartridge) -> Vec<u8> {
+    (0..8).map(|i| cart.get_ppu(VAddr::new(i * 0x400).unwrap())).collect()
+}
+
+#[test]
+fn mmc3_banks() {
+    let rom = paged(4, 4, 2);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut cart = Registry::new().build(&rom).unwrap();
+    let (prg, chr) = (prg_pages, chr_pages);
+
+    for (reg, bank) in [4, 7, 8, 9, 10, 11, 2, 3].iter().enumerate() {
+        cart.set(0x8000, reg as u8);
+        cart.set(0x8001, *bank);
+    }
+    assert_eq!(prg(&cart), [2, 3, 6, 7]);
+    assert_eq!(chr(&cart), [4, 5, 6, 7, 8, 9, 10, 11]);
+
+    cart.set(0x8000, 0xc0);
+    assert_eq!(prg(&cart), [6, 3, 2, 7]);
+    assert_eq!(chr(&cart), [8, 9, 10, 11, 4, 5, 6, 7]);
+
+    // Work RAM can be write protected or disabled entirely.
+    cart.set(0x6000, 0x11);
+    cart.set(0xa001, 0xc0);
+    cart.set(0x6000, 0x22);
+    assert_eq!(cart.get(0x6000), 0x11);
+    cart.set(0xa001, 0x00);
+    assert_eq!(cart.get(0x6000), 0x00);
+}
+
+#[test]
+fn mmc3_irq() {
+    // Submapper, latch, then whether IRQ is raised after each rise of A12.
+    let cases = [
+        (0, 2, [false, false, true, false, false, true]),
+        (0, 0, [true; 6]),
+        (4, 2, [false, false, true, false, false, true]),
+        (4, 0, [true, false, false, false, false, false]),
+    ];
+    for &(submapper, latch, irqs) in cases.iter() {
+        let rom = banked(4, submapper, 4, 1, &[]);
+        let rom = Rom::parse(&rom).unwrap();
+        let mut cart = Registry::new().build(&rom).unwrap();
+        cart.set(0xc000, latch);
+        cart.set(0xc001, 0);
+        cart.set(0xe001, 0);
+
+        let mut found = Vec::new();
+        for _ in 0..6 {
+            cart.snoop(VAddr::new(0x0000).unwrap());
+            (0..3).for_each(|_| cart.clock());
+            cart.snoop(VAddr::new(0x1000).unwrap());
+            found.push(cart.irq());
+            cart.set(0xe000, 0);
+            cart.set(0xe001, 0);
+        }
+        assert_eq!(found, irqs, "submapper {} latch {}", submapper, latch);
+
+        // A12 has to stay low for a while before rising again counts.
+        cart.snoop(VAddr::new(0x0000).unwrap());
+        cart.snoop(VAddr::new(0x1000).unwrap());
+        assert!(!cart.irq());
+    }
+}
+
+// Sets up the IRQ counter with sprites drawn from $1000, then waits for the IRQ:
+// LDA #$08; STA $2000; LDA #$1E; STA $2001; LDA #$10; STA $C000; STA $C001; STA $E001; CLI;
+// NOP; JMP $C026; IRQ: LDA #$42; STA $6000; JMP *
+const MMC3_IRQ: &[u8] = &[
+    0xa9, 0x08, 0x8d, 0x00, 0x20, 0xa9, 0x1e, 0x8d, 0x01, 0x20, 0xa9, 0x10, 0x8d, 0x00, 0xc0, 0x8d,
+    0x01, 0xc0, 0x8d, 0x01, 0xe0, 0x58, 0xea, 0x4c, 0x26, 0xc0, 0xa9, 0x42, 0x8d, 0x00, 0x60, 0x4c,
+    0x2f, 0xc0,
+];
+
+#[test]
+fn mmc3_scanline_irq() {
+    let mut rom = banked(4, 0, 4, 1, MMC3_IRQ);
+    // IRQ vector
+    for bank in 0..4 {
+        let start = 16 + bank * 0x4000 + 0x3ffe;
+        rom[start..start + 2].copy_from_slice(&[0x2a, 0xc0]);
+    }
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    for _ in 0..3 {
+        nes.run_frame().unwrap();
+    }
+    assert_eq!(nes.get_mem(0x6000), 0x42);
+}
+
+#[test]
+fn mmc5_banks() {
+    let rom = paged(5, 4, 4);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut cart = Registry::new().build(&rom).unwrap();
+
+    // Starts with 8KiB banks and the last one at $E000.
+    cart.set(0x5114, 0x81);
+    cart.set(0x5115, 0x82);
+    cart.set(0x5116, 0x83);
+    assert_eq!(prg_pages(&cart), [1, 2, 3, 7]);
+    cart.set(0x5117, 0x85);
+    for &(mode, pages) in [(0, [4, 5, 6, 7]), (1, [2, 3, 4, 5]), (2, [2, 3, 3, 5])].iter() {
+        cart.set(0x5100, mode);
+        assert_eq!(prg_pages(&cart), pages, "PRG mode {}", mode);
+    }
+
+    // PRG RAM can be banked in at $6000 and $8000-$DFFF, once it's unlocked.
+    cart.set(0x5100, 3);
+    cart.set(0x5102, 2);
+    cart.set(0x5103, 1);
+    cart.set(0x5113, 1);
+    cart.set(0x5114, 0x01);
+    cart.set(0x6000, 0x11);
+    assert_eq!(cart.get(0x8000), 0x11);
+    cart.set(0x8000, 0x22);
+    assert_eq!(cart.get(0x6000), 0x22);
+    cart.set(0x5103, 0);
+    cart.set(0x6000, 0x33);
+    assert_eq!(cart.get(0x6000), 0x22);
+
+    cart.set(0x5101, 3);
+    (0..8).for_each(|i| cart.set(0x5120 + i, 8 + i as u8));
+    assert_eq!(chr_pages(&cart), [8, 9, 10, 11, 12, 13, 14, 15]);
+    // The background set covers both pattern tables, and is used while it was written last.
+    (0..4).for_each(|i| cart.set(0x5128 + i, 20 + i as u8));
+    assert_eq!(chr_pages(&cart), [20, 21, 22, 23, 20, 21, 22, 23]);
+    cart.set(0x5101, 1);
+    cart.set(0x5123, 2);
+    cart.set(0x5127, 3);
+    assert_eq!(chr_pages(&cart), [8, 9, 10, 11, 12, 13, 14, 15]);
+
+    cart.set(0x5205, 200);
+    cart.set(0x5206, 100);
+    assert_eq!([cart.get(0x5205), cart.get(0x5206)], [0x20, 0x4e]);
+}
+
+#[test]
+fn mmc5_nametables() {
+    let rom = paged(5, 4, 4);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut cart = Registry::new().build(&rom).unwrap();
+    let vram = [Nametable::new(), Nametable::new()];
+    let addr = |addr| VAddr::new(addr).unwrap();
+
+    cart.set(0x5104, 2);
+    cart.set(0x5c05, 0x77);
+    cart.set(0x5104, 0);
+    // VRAM, then ExRAM, then the fill tile.
+    cart.set(0x5105, 0b11_10_01_00);
+    cart.set(0x5106, 0x42);
+    cart.set(0x5107, 2);
+
+    cart.set_nametable(addr(0x2000), 0x11, &vram);
+    cart.set_nametable(addr(0x2400), 0x22, &vram);
+    assert_eq!([vram[0].read(0), vram[1].read(0)], [0x11, 0x22]);
+    assert_eq!(cart.get_nametable(addr(0x2805), &vram), 0x77);
+    assert_eq!(cart.get_nametable(addr(0x2c05), &vram), 0x42);
+    assert_eq!(cart.get_nametable(addr(0x2fc0), &vram), 0xaa);
+}
+
+#[test]
+fn mmc5_scanlines() {
+    let rom = paged(5, 4, 4);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut cart = Registry::new().build(&rom).unwrap();
+    let vram = [Nametable::new(), Nametable::new()];
+    let addr = |addr| VAddr::new(addr).unwrap();
+    // Reading the same nametable byte three times starts a line, at its third tile.
+    let line = |cart: &mut Cartridge| (0..3).for_each(|_| cart.snoop(addr(0x2002)));
+
+    cart.set(0x5101, 3);
+    cart.set(0x5203, 2);
+    cart.set(0x5204, 0x80);
+    cart.set(0x5104, 2);
+    cart.set(0x5c02, 0xc5);
+    cart.set(0x5104, 1);
+
+    // Extended attributes pick the palette and a 4KiB CHR bank for each tile.
+    line(&mut cart);
+    assert_eq!(cart.get(0x5204), 0x40);
+    cart.snoop(addr(0x23c0));
+    assert_eq!(cart.get_nametable(addr(0x23c0), &vram), 0xff);
+    cart.snoop(addr(0x0000));
+    assert_eq!(cart.get_ppu(addr(0x0000)), 20);
+
+    // The split replaces the leftmost four tiles, with its own nametable and CHR bank.
+    cart.set(0x5200, 0x84);
+    cart.set(0x5202, 1);
+    cart.set(0x5c02, 0x10);
+    cart.set(0x5fc0, 0b0000_1100);
+    line(&mut cart);
+    assert_eq!(cart.get_nametable(addr(0x2002), &vram), 0x10);
+    cart.snoop(addr(0x23c0));
+    assert_eq!(cart.get_nametable(addr(0x23c0), &vram), 0xff);
+    cart.snoop(addr(0x0100));
+    assert_eq!(cart.get_ppu(addr(0x0100)), 4);
+    assert!(!cart.irq());
+
+    line(&mut cart);
+    assert!(cart.irq());
+    assert_eq!(cart.get(0x5204), 0xc0);
+    assert!(!cart.irq());
+
+    // The frame ends once the PPU stops reading.
+    (0..3).for_each(|_| cart.clock());
+    assert_eq!(cart.get(0x5204), 0x00);
+}
+
+// Sets up the IRQ for line 64 with the given PPU mask and the APU's IRQ off, then waits for it:
+// LDA #mask; STA $2001; LDA #$40; STA $4017; LDA #2; STA $5102; LDA #1; STA $5103; LDA #$40;
+// STA $5203; LDA #$80; STA $5204; CLI; NOP; JMP $E02F; IRQ: LDA #$42; STA $6000; JMP *
+fn mmc5_irq(mask: u8) -> Vec<u8> {
+    #[rustfmt::skip]
+    let code = [
+        0xa9, mask, 0x8d, 0x01, 0x20, 0xa9, 0x40, 0x8d, 0x17, 0x40, 0xa9, 0x02, 0x8d, 0x02, 0x51,
+        0xa9, 0x01, 0x8d, 0x03, 0x51, 0xa9, 0x40, 0x8d, 0x03, 0x52, 0xa9, 0x80, 0x8d, 0x04, 0x52,
+        0x58, 0xea, 0x4c, 0x2f, 0xe0, 0xa9, 0x42, 0x8d, 0x00, 0x60, 0x4c, 0x38, 0xe0,
+    ];
+    let mut rom = banked(5, 0, 4, 1, &[]);
+    // Only the last 8KiB is mapped at power on.
+    let last = 16 + 3 * 0x4000 + 0x2000;
+    rom[last + 0x10..last + 0x10 + code.len()].copy_from_slice(&code);
+    rom[last + 0x1ffc..last + 0x2000].copy_from_slice(&[0x10, 0xe0, 0x33, 0xe0]);
+    rom
+}
+
+#[test]
+fn mmc5_scanline_irq() {
+    let rom = mmc5_irq(0x1e);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    for _ in 0..3 {
+        nes.run_frame().unwrap();
+    }
+    assert_eq!(nes.get_mem(0x6000), 0x42);
+}
+
+#[test]
+fn mmc5_rendering_off() {
+    let rom = mmc5_irq(0x00);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    for _ in 0..3 {
+        nes.run_frame().unwrap();
+    }
+    assert_eq!(nes.get_mem(0x6000), 0x00);
+
+    // Without any fetches to watch, it never sees the PPU drawing a frame.
+    for _ in 0..10_000 {
+        nes.step_instruction().unwrap();
+        assert_eq!(nes.get_mem(0x5204) & 0x40, 0);
+    }
+}
+
+#[test]
+fn sunsoft4() {
+    let rom = paged(68, 4, 32);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut cart = Registry::new().build(&rom).unwrap();
+    let vram = [Nametable::new(), Nametable::new()];
+    let addr = |addr| VAddr::new(addr).unwrap();
+
+    (0..4).for_each(|i| cart.set(0x8000 + i * 0x1000, i as u8 + 1));
+    cart.set(0xf000, 0x01);
+    assert_eq!(prg_pages(&cart), [2, 3, 6, 7]);
+    assert_eq!(chr_pages(&cart), [2, 3, 4, 5, 6, 7, 8, 9]);
+
+    // Horizontal mirroring of the last 128KiB of CHR ROM, which can't be written.
+    cart.set(0xe000, 0x11);
+    cart.set(0xc000, 3);
+    cart.set(0xd000, 5);
+    cart.set_nametable(addr(0x2000), 0x42, &vram);
+    assert_eq!(cart.get_nametable(addr(0x2400), &vram), 131);
+    assert_eq!(cart.get_nametable(addr(0x2800), &vram), 133);
+    assert_eq!(vram[0].read(0), 0x00);
+
+    // Back to the console's nametables, with the upper one on every screen.
+    cart.set(0xe000, 0x03);
+    cart.set_nametable(addr(0x2000), 0x42, &vram);
+    assert_eq!(cart.get_nametable(addr(0x2c00), &vram), 0x42);
+    assert_eq!(vram[1].read(0), 0x42);
+}
+
+// Random headers past the magic number, and random lengths, must never panic.
+#[test]
+fn fuzz_headers() {
+    let valid = rom(0x00, &[]);
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+
+    for _ in 0..10_000 {
+        let mut rom = valid.clone();
+        for _ in 0..next() % 8 {
+            let idx = 4 + (next() % 12) as usize;
+            rom[idx] = next() as u8;
+        }
+        rom.truncate((next() % rom.len() as u64) as usize + 1);
+        if let Ok(parsed) = Rom::parse(&rom) {
+            assert!(parsed.prg.len() + parsed.chr.len() + 16 <= rom.len());
+            // Unknown mappers are an error, but anything that loads must not panic either.
+            let _ = Nes::new(&parsed);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use mynes::input::{Aim, FourScore, Mouse, MouseState, PowerPad, Zapper};
+use mynes::{Buttons, Nes, Port, Rom};
+
+// An NROM image running the given code from $8000, with blank CHR ROM.
+fn rom(code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 16 + 0x4000 + 0x2000];
+    rom[..16].copy_from_slice(&[b'N', b'E', b'S', 0x1a, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let prg = &mut rom[16..16 + 0x4000];
+    prg[..code.len()].copy_from_slice(code);
+    prg[0x3ffc..0x3ffe].copy_from_slice(&[0x00, 0x80]);
+    rom
+}
+
+const STROBED: usize = 4;
+const READS: usize = 40;
+
+// Reads both ports four times with the strobe held high, then 36 times after it's released,
+// keeping what $4016 returned at $0200 and what $4017 returned at $0300.
+const READ_PORTS: &[u8] = &[
+    0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa2, 0x00, // LDA #1; STA $4016; LDX #0
+    0xad, 0x16, 0x40, 0x9d, 0x00, 0x02, // LDA $4016; STA $0200,X
+    0xad, 0x17, 0x40, 0x9d, 0x00, 0x03, // LDA $4017; STA $0300,X
+    0xe8, 0xe0, STROBED as u8, 0xd0, 0xef, // INX; CPX #4; BNE $8007
+    0xa9, 0x00, 0x8d, 0x16, 0x40, // LDA #0; STA $4016
+    0xad, 0x16, 0x40, 0x9d, 0x00, 0x02, // LDA $4016; STA $0200,X
+    0xad, 0x17, 0x40, 0x9d, 0x00, 0x03, // LDA $4017; STA $0300,X
+    0xe8, 0xe0, READS as u8, 0xd0, 0xef, // INX; CPX #40; BNE $801D
+    0x4c, 0x2e, 0x80, // JMP *
+];
+
+// Runs READ_PORTS with whatever devices `connect` plugs in, returning what each port read.
+fn read_ports(connect: impl FnOnce(&mut Nes)) -> [Vec<u8>; 2] {
+    let rom = rom(READ_PORTS);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    connect(&mut nes);
+    nes.run().unwrap();
+
+    let mut read = |base: u16| (0..READS as u16).map(|i| nes.get_mem(base + i)).collect();
+    [read(0x200), read(0x300)]
+}
+
+// What a port reads while a device shifts out the given bytes, least significant bit first, on
+// D0. The upper bits are open bus, left over from the high byte of `LDA $4016`.
+fn serial(strobed: u8, bytes: &[u8]) -> Vec<u8> {
+    let bits = bytes.iter().flat_map(|&byte| (0..8).map(move |i| byte >> i & 1));
+    let reads = vec![strobed; STROBED].into_iter().chain(bits).chain(std::iter::repeat(1));
+    reads.take(READS).map(|bit| 0x40 | bit).collect()
+}
+
+#[test]
+fn controllers() {
+    let [one, two] = read_ports(|nes| {
+        nes.set_buttons(Port::One, Buttons::from(Buttons::A | Buttons::START | Buttons::RIGHT));
+        nes.set_buttons(Port::Two, Buttons::from(Buttons::B));
+    });
+    // A is read over and over while strobed, then all eight buttons in turn, then 1s.
+    assert_eq!(one, serial(1, &[0x89]));
+    assert_eq!(two, serial(0, &[0x02]));
+    // Only D0-D4 are driven, so the rest comes from the high byte of the address.
+    assert!(one.iter().chain(&two).all(|read| read & 0xe0 == 0x40));
+}
+
+#[test]
+fn four_score() {
+    let one = FourScore::new(Port::One);
+    let two = FourScore::new(Port::Two);
+    one.buttons(0).set(Buttons { a: true, ..Buttons::default() });
+    one.buttons(1).set(Buttons::from(Buttons::B | Buttons::UP));
+    two.buttons(1).set(Buttons::from(Buttons::RIGHT));
+
+    let [first, second] = read_ports(|nes| {
+        nes.connect(Port::One, Box::new(one));
+        nes.connect(Port::Two, Box::new(two));
+    });
+    // Both pads, then the signature telling which port the adapter is on.
+    assert_eq!(first, serial(1, &[0x01, 0x12, 0x08]));
+    assert_eq!(second, serial(0, &[0x00, 0x80, 0x04]));
+}
+
+// The SNES mouse sends its 32 bit report most significant bit first.
+#[test]
+fn mouse() {
+    let mouse = Mouse::new();
+    let state = mouse.state();
+    state.set(MouseState { dx: 5, dy: -300, left: true, right: false });
+
+    let [_, reads] = read_ports(|nes| nes.connect(Port::Two, Box::new(mouse)));
+    let report = [0x00, 0x41, 0xff, 0x05].iter().map(|byte: &u8| byte.reverse_bits());
+    assert_eq!(reads, serial(0, &report.collect::<Vec<_>>()));
+    // Reporting the motion clears it, while the buttons stay held.
+    assert_eq!(state.get(), MouseState { dx: 0, dy: 0, left: true, right: false });
+}
+
+// The Power Pad sends two streams at once, on D3 and D4.
+#[test]
+fn power_pad() {
+    let pad = PowerPad::new();
+    // Buttons 2 and 11 come through D3, and 4 and 8 through D4.
+    pad.pressed().set(1 << 1 | 1 << 10 | 1 << 3 | 1 << 7);
+
+    let [_, reads] = read_ports(|nes| nes.connect(Port::Two, Box::new(pad)));
+    let d3 = serial(1, &[0b0100_0001]);
+    // D4 only has four buttons, followed by 1s.
+    let d4 = serial(1, &[0b1111_1001]);
+    let expected: Vec<u8> =
+        d3.iter().zip(d4).map(|(d3, d4)| 0x40 | (d3 & 1) << 3 | (d4 & 1) << 4).collect();
+    assert_eq!(reads, expected);
+}
+
+// Fills the screen with the given color, turning rendering off while the palette is written,
+// then spins.
+fn backdrop(color: u8) -> Vec<u8> {
+    rom(&[
+        0xa9, 0x00, 0x8d, 0x01, 0x20, // LDA #0; STA $2001
+        0xa9, 0x3f, 0x8d, 0x06, 0x20, 0xa9, 0x00, 0x8d, 0x06, 0x20, // LDA #$3F; STA $2006; ...
+        0xa9, color, 0x8d, 0x07, 0x20, // LDA #color; STA $2007
+        0xa9, 0x0a, 0x8d, 0x01, 0x20, // LDA #$0A; STA $2001
+        0xea, 0x4c, 0x19, 0x80, // NOP; JMP $8019
+    ])
+}
+
+// Returns how many CPU cycles of a frame the Zapper saw light for, and the trigger bits read.
+fn zapper(color: u8, trigger: bool) -> (usize, u8) {
+    let rom = backdrop(color);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    let zapper = Zapper::new();
+    zapper.aim().set(Aim { x: 128, y: 100, trigger });
+    nes.connect(Port::Two, Box::new(zapper));
+
+    for _ in 0..2 {
+        nes.run_frame().unwrap();
+    }
+    let (mut lit, mut triggers) = (0, 0);
+    for _ in 0..29781 {
+        nes.step_cycle().unwrap();
+        let read = nes.get_mem(0x4017);
+        // D3 reads 0 while light is detected.
+        lit += usize::from(read & 0x08 == 0);
+        triggers |= read & 0x10;
+    }
+    (lit, triggers)
+}
+
+#[test]
+fn zapper_light() {
+    // The photodiode sees the white screen for about 26 scanlines after the beam passes the aim.
+    let (lit, triggers) = zapper(0x30, false);
+    assert!((25 * 341 / 3..=27 * 341 / 3).contains(&lit), "lit for {} cycles", lit);
+    assert_eq!(triggers, 0);
+
+    let (lit, triggers) = zapper(0x0f, true);
+    assert_eq!(lit, 0);
+    assert_eq!(triggers, 0x10);
+}
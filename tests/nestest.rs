@@ -2,11 +2,12 @@ use std::io;
 
 use mynes::Nes;
 use mynes::Rom;
+use mynes::StateError;
 
 #[test]
 fn nestest() -> io::Result<()> {
     let rom = Rom::parse(include_bytes!("roms/nestest.nes")).unwrap();
-    let mut nes = Nes::new(&rom);
+    let mut nes = Nes::new(&rom).unwrap();
     nes.set_pc(0xc000);
     let e = nes.run();
 
@@ -20,3 +21,107 @@ fn nestest() -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn step_instruction() {
+    let rom = Rom::parse(include_bytes!("roms/nestest.nes")).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    nes.set_pc(0xc000);
+
+    // JMP $C5F5
+    assert!(nes.step_instruction().unwrap());
+    assert_eq!(nes.cpu().pc.0, 0xc5f5);
+}
+
+#[test]
+fn save_state() {
+    let rom = Rom::parse(include_bytes!("roms/nestest.nes")).unwrap();
+    // At a few points part way through drawing a frame.
+    for steps in (800..4000).step_by(400) {
+        let mut nes = Nes::new(&rom).unwrap();
+        // Long enough for the menu to be drawn.
+        for _ in 0..10 {
+            nes.run_frame().unwrap();
+        }
+        for _ in 0..steps {
+            nes.step_instruction().unwrap();
+        }
+        let state = nes.save_state().unwrap();
+        let drawn = nes.frame().clone();
+        nes.run_frame().unwrap();
+        let finished = nes.frame().clone();
+        nes.run_frame().unwrap();
+
+        // The picture isn't part of the state, so the part already drawn is copied over.
+        let mut loaded = Nes::new(&rom).unwrap();
+        loaded.load_state(&state).unwrap();
+        *loaded.frame() = drawn;
+        loaded.run_frame().unwrap();
+        assert!(*loaded.frame() == finished, "{} steps", steps);
+        loaded.run_frame().unwrap();
+
+        assert_eq!(format!("{:?}", loaded.cpu()), format!("{:?}", nes.cpu()));
+        assert!(*loaded.frame() == *nes.frame());
+    }
+
+    let mut nes = Nes::new(&rom).unwrap();
+    assert_eq!(nes.load_state(b"not a state"), Err(StateError::Magic));
+    // Only between instructions.
+    nes.step_cycle().unwrap();
+    assert_eq!(nes.save_state(), Err(StateError::Busy));
+}
+
+#[test]
+fn corrupt_state() {
+    let rom = Rom::parse(include_bytes!("roms/nestest.nes")).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    nes.run_frame().unwrap();
+    let state = nes.save_state().unwrap();
+    // The DMC is saved last before the frame counter and the CPU's cycle count.
+    let dmc = state.len() - 39;
+
+    let cases: &[(usize, &[u8], &str)] = &[
+        (dmc + 4, &[0, 0], "DMC timer"),
+        (dmc + 18, &[0], "DMC bit count"),
+        (dmc + 20, &[0x80], "DMC output"),
+    ];
+    let mut loaded = Nes::new(&rom).unwrap();
+    for &(offset, bytes, what) in cases {
+        let mut corrupt = state.clone();
+        corrupt[offset..offset + bytes.len()].copy_from_slice(bytes);
+        assert_eq!(loaded.load_state(&corrupt), Err(StateError::Invalid(what)));
+    }
+    assert_eq!(loaded.load_state(&state), Ok(()));
+}
+
+// A state that fails to load leaves the machine running as if it was never tried.
+#[test]
+fn failed_load() {
+    let rom = Rom::parse(include_bytes!("roms/nestest.nes")).unwrap();
+    let mut other = Nes::new(&rom).unwrap();
+    for _ in 0..5 {
+        other.run_frame().unwrap();
+    }
+    let state = other.save_state().unwrap();
+    // The DMC output level, near the end once everything else has loaded.
+    let mut corrupt = state.clone();
+    corrupt[state.len() - 19] = 0x80;
+
+    let (mut nes, mut twin) = (Nes::new(&rom).unwrap(), Nes::new(&rom).unwrap());
+    for _ in 0..10 {
+        nes.run_frame().unwrap();
+        twin.run_frame().unwrap();
+    }
+    // Even part way through an instruction.
+    nes.step_cycle().unwrap();
+    twin.step_cycle().unwrap();
+    assert_eq!(nes.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+    assert_eq!(nes.load_state(&corrupt), Err(StateError::Invalid("DMC output")));
+
+    for _ in 0..2 {
+        nes.run_frame().unwrap();
+        twin.run_frame().unwrap();
+    }
+    assert_eq!(nes.save_state(), twin.save_state());
+    assert!(*nes.frame() == *twin.frame());
+}
@@ -0,0 +1,167 @@
+use std::path::{Path, PathBuf};
+
+use image::{ImageBuffer, Pixel, RgbImage};
+use mynes::{Nes, Rom};
+
+// Golden images live in tests/screenshots/<name>.png. On a mismatch the actual output is written
+// next to it as <name>.actual.png, which can be inspected and renamed to accept it.
+fn screenshot(name: &str) -> (PathBuf, PathBuf) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/screenshots");
+    (dir.join(format!("{}.png", name)), dir.join(format!("{}.actual.png", name)))
+}
+
+fn test_rom(name: &str, rom: &[u8], frames: usize) {
+    let rom = Rom::parse(rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    for _ in 0..frames {
+        if !nes.run_frame().unwrap() {
+            break;
+        }
+    }
+
+    let frame = nes.frame();
+    let actual: RgbImage =
+        ImageBuffer::from_fn(frame.width(), frame.height(), |x, y| frame.get_pixel(x, y).to_rgb());
+
+    let (expected, output) = screenshot(name);
+    let matches = match image::open(&expected) {
+        Ok(golden) => golden.to_rgb8() == actual,
+        Err(_) => false,
+    };
+    if !matches {
+        actual.save(&output).unwrap();
+        panic!("{} does not match {}", output.display(), expected.display());
+    }
+}
+
+macro_rules! test_file {
+    ($name:ident($path:expr, $frames:expr)) => {
+        #[test]
+        fn $name() {
+            test_rom(stringify!($name), include_bytes!(concat!("roms/", $path, ".nes")), $frames)
+        }
+    };
+
+    ([i]$name:ident($path:expr, $frames:expr)) => {
+        #[test]
+        #[ignore]
+        fn $name() {
+            test_rom(stringify!($name), include_bytes!(concat!("roms/", $path, ".nes")), $frames)
+        }
+    };
+}
+
+macro_rules! sprite_hit_test {
+    ($name:ident($id:literal)) => {
+        test_file!([i]$name(
+            concat!("nes-test-roms/sprite_hit_tests_2005.10.05/", $id, ".", stringify!($name)),
+            60
+        ));
+    };
+}
+
+// The menu, which nestest shows until a button is pressed.
+test_file!(nestest("nestest", 10));
+
+// These need the nes-test-roms submodule, and none have recorded screenshots yet.
+sprite_hit_test!(basics("01"));
+sprite_hit_test!(alignment("02"));
+sprite_hit_test!(corners("03"));
+sprite_hit_test!(flip("04"));
+sprite_hit_test!(left_clip("05"));
+sprite_hit_test!(right_edge("06"));
+sprite_hit_test!(screen_bottom("07"));
+sprite_hit_test!(double_height("08"));
+sprite_hit_test!(timing_basics("09"));
+sprite_hit_test!(timing_order("10"));
+sprite_hit_test!(edge_timing("11"));
+
+test_file!([i]full_palette("nes-test-roms/full_palette/full_palette", 10));
+test_file!([i]full_palette_smooth("nes-test-roms/full_palette/full_palette_smooth", 10));
+test_file!([i]flowing_palette("nes-test-roms/full_palette/flowing_palette", 10));
+
+// An NROM image that copies `oam` into OAM, hiding the rest of the sprites below the screen,
+// writes `ctrl` to $2000 and `mask` to $2001, then spins. Its NMI handler counts frames at $00.
+// Tile 0 is solid, and so is the background, since every nametable entry points at it.
+fn sprites(oam: &[[u8; 4]], ctrl: u8, mask: u8) -> Vec<u8> {
+    let mut rom = vec![0; 16 + 0x4000 + 0x2000];
+    rom[..16].copy_from_slice(&[b'N', b'E', b'S', 0x1a, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let prg = &mut rom[16..16 + 0x4000];
+    #[rustfmt::skip]
+    prg[..0x24].copy_from_slice(&[
+        0xa9, 0x00, 0x8d, 0x01, 0x20, 0x8d, 0x03, 0x20, // LDA #0; STA $2001; STA $2003
+        0xa2, 0x00, 0xbd, 0x00, 0x81, 0x8d, 0x04, 0x20, // LDX #0; LDA $8100,X; STA $2004
+        0xe8, 0xd0, 0xf7, // INX; BNE $800A
+        0xa9, ctrl, 0x8d, 0x00, 0x20, 0xa9, mask, 0x8d, 0x01, 0x20, // STA $2000; STA $2001
+        0xea, 0x4c, 0x1d, 0x80, // NOP; JMP $801D
+        0xe6, 0x00, 0x40, // NMI: INC $00; RTI
+    ]);
+    let table = oam.iter().flatten().copied().chain(std::iter::repeat(0xff));
+    prg[0x100..0x200].iter_mut().zip(table).for_each(|(byte, val)| *byte = val);
+    prg[0x3ffa..0x3ffe].copy_from_slice(&[0x21, 0x80, 0x00, 0x80]);
+    rom[16 + 0x4000..16 + 0x4008].copy_from_slice(&[0xff; 8]);
+    rom
+}
+
+// Runs the given number of frames, returning $2002 as it was at the start of the last vblank and
+// the number of NMIs.
+fn run_sprites(rom: &[u8], frames: usize) -> (u8, u8) {
+    let rom = Rom::parse(rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    for _ in 0..frames {
+        nes.run_frame().unwrap();
+    }
+    (nes.get_mem(0x2002), nes.get_mem(0x00))
+}
+
+const HIT: u8 = 0x40;
+const OVERFLOW: u8 = 0x20;
+const VBLANK: u8 = 0x80;
+
+#[test]
+fn sprite_hit() {
+    let hits = |oam: &[[u8; 4]], mask| run_sprites(&sprites(oam, 0, mask), 2).0 & HIT != 0;
+    assert!(hits(&[[50, 0, 0, 100]], 0x1e));
+    // Both the sprite and the background have to be shown.
+    assert!(!hits(&[[50, 0, 0, 100]], 0x0a));
+    assert!(!hits(&[[50, 0, 0, 100]], 0x14));
+    // Only sprite 0 counts.
+    assert!(!hits(&[[0xff, 0, 0, 0], [50, 0, 0, 100]], 0x1e));
+    // Nor is there a hit in the leftmost 8 pixels while they're clipped, or at x = 255.
+    assert!(hits(&[[50, 0, 0, 0]], 0x1e));
+    assert!(!hits(&[[50, 0, 0, 0]], 0x18));
+    assert!(!hits(&[[50, 0, 0, 255]], 0x1e));
+}
+
+#[test]
+fn sprite_overflow() {
+    let overflows = |count| {
+        let oam = vec![[50, 0, 0, 100]; count];
+        run_sprites(&sprites(&oam, 0, 0x1e), 2).0 & OVERFLOW != 0
+    };
+    assert!(!overflows(8));
+    assert!(overflows(9));
+    // Evaluation only happens while rendering.
+    let oam = vec![[50, 0, 0, 100]; 9];
+    assert_eq!(run_sprites(&sprites(&oam, 0, 0x00), 2).0 & OVERFLOW, 0);
+}
+
+#[test]
+fn vbl_nmi() {
+    let rom = sprites(&[], 0x80, 0x1e);
+    let rom = Rom::parse(&rom).unwrap();
+    let mut nes = Nes::new(&rom).unwrap();
+    for frame in 1..=5 {
+        // Frames end as soon as vblank starts, before the CPU gets to the NMI handler.
+        nes.run_frame().unwrap();
+        assert_eq!(nes.get_mem(0x00), frame - 1);
+        // Reading $2002 clears the flag.
+        assert_ne!(nes.get_mem(0x2002) & VBLANK, 0);
+        assert_eq!(nes.get_mem(0x2002) & VBLANK, 0);
+    }
+
+    // No NMIs while they're disabled, but the flag is still set.
+    let (status, nmis) = run_sprites(&sprites(&[], 0x00, 0x1e), 5);
+    assert_ne!(status & VBLANK, 0);
+    assert_eq!(nmis, 0);
+}